@@ -0,0 +1,395 @@
+//!
+//! Node cache
+//!
+//! A `TreeStore` wrapper that keeps recently used decoded nodes in memory, so
+//! the upper levels of the tree, which every walk passes through, are read
+//! from the inner store only once.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::{Result, SmtError};
+use crate::store::TreeStore;
+use crate::types::{HashValue, Node};
+
+/// Rough memory use of one cached node with `N` byte hashes, counting the
+/// key, the node and the LRU bookkeeping
+const fn entry_bytes<const N: usize>() -> usize {
+    2 * std::mem::size_of::<HashValue<N>>()
+        + std::mem::size_of::<Node<N>>()
+        + 2 * std::mem::size_of::<u64>()
+}
+
+/// How much a `CacheStore` may hold
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheBudget {
+    Entries(usize),
+    /// Approximate, based on the size of a cached entry
+    Bytes(usize),
+}
+
+impl CacheBudget {
+    fn entries<const N: usize>(self) -> usize {
+        match self {
+            CacheBudget::Entries(n) => n,
+            CacheBudget::Bytes(n) => n / entry_bytes::<N>(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Default)]
+struct Lru<const N: usize> {
+    /// Node and the tick it was last used at
+    entries: HashMap<HashValue<N>, (Node<N>, u64)>,
+    /// Tick to key, oldest first
+    order: BTreeMap<u64, HashValue<N>>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+impl<const N: usize> Lru<N> {
+    fn get(&mut self, key: HashValue<N>) -> Option<Node<N>> {
+        let tick = self.tick;
+        let (node, used) = self.entries.get_mut(&key)?;
+        self.order.remove(used);
+        self.order.insert(tick, key);
+        *used = tick;
+        self.tick += 1;
+        Some(*node)
+    }
+
+    fn insert(&mut self, key: HashValue<N>, node: Node<N>, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        if let Some((_, used)) = self.entries.insert(key, (node, self.tick)) {
+            self.order.remove(&used);
+        }
+        self.order.insert(self.tick, key);
+        self.tick += 1;
+
+        while self.entries.len() > capacity {
+            let oldest = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+            if let Some(key) = self.order.remove(&oldest) {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &HashValue<N>) {
+        if let Some((_, used)) = self.entries.remove(key) {
+            self.order.remove(&used);
+        }
+    }
+}
+
+/// A read-through LRU cache of decoded nodes over another `TreeStore`.
+///
+/// In write-back mode new nodes stay in memory until `flush`, which
+/// `set_root` also does, so a commit always reaches the inner store. Pending
+/// nodes count against the budget: once there are more than it allows, they
+/// are flushed early. Values are never cached. `N` is the width of the
+/// tree's hashes.
+pub struct CacheStore<S: TreeStore<N>, const N: usize = 32> {
+    inner: S,
+    capacity: usize,
+    lru: Mutex<Lru<N>>,
+    write_back: bool,
+    /// Nodes not yet written to the inner store, in write-back mode
+    dirty: HashMap<HashValue<N>, Node<N>>,
+}
+
+impl<S: TreeStore<N>, const N: usize> CacheStore<S, N> {
+    /// A cache that writes nodes through to `inner` straight away
+    pub fn new(inner: S, budget: CacheBudget) -> Self {
+        Self {
+            inner,
+            capacity: budget.entries::<N>(),
+            lru: Mutex::new(Lru::default()),
+            write_back: false,
+            dirty: HashMap::new(),
+        }
+    }
+
+    /// A cache that holds new nodes until `flush`
+    pub fn write_back(inner: S, budget: CacheBudget) -> Self {
+        Self {
+            write_back: true,
+            ..Self::new(inner, budget)
+        }
+    }
+
+    /// Write the pending nodes to the inner store. A node stays pending until
+    /// its write succeeds, so a failed flush can be retried.
+    pub fn flush(&mut self) -> Result<(), N> {
+        let keys: Vec<HashValue<N>> = self.dirty.keys().copied().collect();
+        for key in keys {
+            if let Some(&node) = self.dirty.get(&key) {
+                self.inner.set_node(key, node)?;
+                self.dirty.remove(&key);
+                let capacity = self.capacity;
+                self.lru()?.insert(key, node, capacity);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.lru().map(|lru| lru.stats).unwrap_or_default()
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Unwrap the inner store. Pending writes are lost unless flushed first.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn lru(&self) -> Result<MutexGuard<'_, Lru<N>>, N> {
+        self.lru
+            .lock()
+            .map_err(|_| SmtError::Storage("node cache lock poisoned".into()))
+    }
+}
+
+impl<S: TreeStore<N>, const N: usize> TreeStore<N> for CacheStore<S, N> {
+    fn get_root(&self) -> Result<Option<HashValue<N>>, N> {
+        self.inner.get_root()
+    }
+
+    fn set_root(&mut self, root: HashValue<N>) -> Result<(), N> {
+        self.flush()?;
+        self.inner.set_root(root)
+    }
+
+    fn get_node(&self, key: HashValue<N>) -> Result<Option<Node<N>>, N> {
+        {
+            let mut lru = self.lru()?;
+            if let Some(node) = self.dirty.get(&key).copied().or_else(|| lru.get(key)) {
+                lru.stats.hits += 1;
+                return Ok(Some(node));
+            }
+            lru.stats.misses += 1;
+        }
+
+        // Unlocked, so other readers don't wait on the inner store
+        let node = self.inner.get_node(key)?;
+        if let Some(node) = node {
+            self.lru()?.insert(key, node, self.capacity);
+        }
+        Ok(node)
+    }
+
+    fn set_node(&mut self, key: HashValue<N>, node: Node<N>) -> Result<(), N> {
+        if self.write_back {
+            self.dirty.insert(key, node);
+            if self.dirty.len() > self.capacity {
+                self.flush()?;
+            }
+        } else {
+            self.inner.set_node(key, node)?;
+            let capacity = self.capacity;
+            self.lru()?.insert(key, node, capacity);
+        }
+        Ok(())
+    }
+
+    fn delete_node(&mut self, key: &HashValue<N>) -> Result<(), N> {
+        self.dirty.remove(key);
+        self.lru()?.remove(key);
+        self.inner.delete_node(key)
+    }
+
+    fn get_value(&self, key: HashValue<N>) -> Result<Option<Vec<u8>>, N> {
+        self.inner.get_value(key)
+    }
+
+    fn set_value(&mut self, key: HashValue<N>, value: &[u8]) -> Result<(), N> {
+        self.inner.set_value(key, value)
+    }
+
+    fn delete_value(&mut self, key: &HashValue<N>) -> Result<(), N> {
+        self.inner.delete_value(key)
+    }
+
+    fn node_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        let mut keys = self.inner.node_keys()?;
+        keys.extend(self.dirty.keys());
+        Ok(keys)
+    }
+
+    fn value_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        self.inner.value_keys()
+    }
+
+    fn pinned_roots(&self) -> Result<Vec<HashValue<N>>, N> {
+        self.inner.pinned_roots()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::TestStore;
+    use crate::{Blake2bHasher, MemoryStore, SmtError, SparseMerkleTree};
+
+    fn cached_tree(store: CacheStore<MemoryStore>) -> SparseMerkleTree<CacheStore<MemoryStore>> {
+        SparseMerkleTree::with_store(store, None)
+    }
+
+    #[test]
+    fn test_lru() {
+        let mut lru = Lru::default();
+        let key = |i: u8| HashValue::digest_of(&[i]);
+        let node = |i: u8| Node::new_leaf(key(i), key(i));
+        for i in 0..3 {
+            lru.insert(key(i), node(i), 3);
+        }
+        // 0 is used, so 1 is the oldest
+        assert_eq!(lru.get(key(0)), Some(node(0)));
+        lru.insert(key(3), node(3), 3);
+        assert_eq!(lru.get(key(1)), None);
+        assert_eq!(lru.get(key(0)), Some(node(0)));
+        assert_eq!(lru.get(key(2)), Some(node(2)));
+        assert_eq!(lru.entries.len(), 3);
+        assert_eq!(lru.order.len(), 3);
+
+        lru.remove(&key(2));
+        assert_eq!(lru.get(key(2)), None);
+        lru.insert(key(4), node(4), 0);
+        assert_eq!(lru.get(key(4)), None);
+        assert_eq!(
+            CacheBudget::Bytes(entry_bytes::<32>() * 10).entries::<32>(),
+            10
+        );
+    }
+
+    #[test]
+    fn test_upper_levels_hit() {
+        let mut tree = cached_tree(CacheStore::new(
+            MemoryStore::new(),
+            CacheBudget::Entries(256),
+        ));
+        for i in 0..1000u16 {
+            assert!(tree.update(&i.to_be_bytes(), b"v").is_ok());
+        }
+
+        let before = tree.store().stats();
+        for i in (0..1000u16).step_by(7) {
+            assert_eq!(tree.get(&i.to_be_bytes()).unwrap(), b"v");
+        }
+        let after = tree.store().stats();
+        assert!(after.hits - before.hits > after.misses - before.misses);
+
+        // every walk passes through the top levels, so they never miss
+        let misses = tree.store().stats().misses;
+        let mut level = vec![tree.get_root()];
+        for _ in 0..4 {
+            let mut next = vec![];
+            for hash in level {
+                match tree.store().get_node(hash).unwrap() {
+                    Some(Node::Internal((left, right))) => next.extend([left, right]),
+                    _ => panic!("expected an internal node"),
+                }
+            }
+            level = next;
+        }
+        assert_eq!(level.len(), 16);
+        assert_eq!(tree.store().stats().misses, misses);
+    }
+
+    #[test]
+    fn test_write_back() {
+        let mut tree = cached_tree(CacheStore::write_back(
+            MemoryStore::new(),
+            CacheBudget::Bytes(1 << 20),
+        ));
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let root = tree.get_root();
+        assert!(tree.store().inner().get_node(root).unwrap().is_none());
+        assert_eq!(tree.get(&[3]).unwrap(), vec![3]);
+
+        assert!(tree.commit().is_ok());
+        let inner = tree.into_store().into_inner();
+        assert_eq!(inner.get_root().unwrap(), Some(root));
+        let tree: SparseMerkleTree<_> = SparseMerkleTree::with_store(inner, Some(root));
+        for i in 0..50u8 {
+            assert_eq!(tree.get(&[i]).unwrap(), vec![i]);
+        }
+    }
+
+    #[test]
+    fn test_failed_flush() {
+        let mut tree: SparseMerkleTree<CacheStore<TestStore>> = SparseMerkleTree::with_store(
+            CacheStore::write_back(TestStore::default(), CacheBudget::Entries(1000)),
+            None,
+        );
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let pending = tree.store().dirty.len();
+        assert!(pending > 0);
+
+        // nothing pending is lost when the inner store fails
+        tree.store_mut().inner.read_only = true;
+        assert!(matches!(tree.commit(), Err(SmtError::Storage(_))));
+        assert_eq!(tree.store().dirty.len(), pending);
+        assert_eq!(tree.get(&[3]).unwrap(), vec![3]);
+
+        tree.store_mut().inner.read_only = false;
+        assert!(tree.commit().is_ok());
+        assert!(tree.store().dirty.is_empty());
+        assert_eq!(tree.store().inner().node_writes, pending);
+
+        // a small budget flushes early
+        let mut store = CacheStore::write_back(MemoryStore::new(), CacheBudget::Entries(4));
+        for i in 0..10u8 {
+            let node = Node::new_leaf(HashValue::digest_of(&[i]), HashValue::digest_of(&[i]));
+            assert!(store
+                .set_node(node.hash::<crate::Blake2sHasher>(), node)
+                .is_ok());
+            assert!(store.dirty.len() <= 4);
+        }
+    }
+
+    #[test]
+    fn test_narrow() {
+        let mut tree: SparseMerkleTree<CacheStore<MemoryStore<20>, 20>, Blake2bHasher, 20> =
+            SparseMerkleTree::with_store(
+                CacheStore::write_back(MemoryStore::new(), CacheBudget::Bytes(1 << 16)),
+                None,
+            );
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        assert!(tree.commit().is_ok());
+        let root = tree.get_root();
+        for i in 0..50u8 {
+            assert_eq!(tree.get(&[i]).unwrap(), vec![i]);
+        }
+        assert!(tree.store().stats().hits > 0);
+
+        let inner = tree.into_store().into_inner();
+        let tree: SparseMerkleTree<_, Blake2bHasher, 20> =
+            SparseMerkleTree::with_store(inner, Some(root));
+        assert_eq!(tree.get(&[9]).unwrap(), vec![9]);
+
+        // narrower entries fit more of them in the same budget
+        let budget = CacheBudget::Bytes(1 << 16);
+        assert!(budget.entries::<20>() > budget.entries::<32>());
+    }
+}
@@ -0,0 +1,104 @@
+//!
+//! Celestia layout
+//!
+//! The tree follows the Go `celestiaorg/smt` layout: leaves are
+//! `sha(0 | path | sha(value))`, internal nodes `sha(1 | left | right)`, empty
+//! subtrees the zero hash, and paths are the hash of the key, with SHA-256 as
+//! the hasher. The test vectors come from an independent model of that
+//! algorithm and haven't been checked against the Go library yet, so matching
+//! Go's roots and proofs is the aim rather than a tested fact until
+//! `testdata/celestia-gen` output replaces them.
+//!
+//! Proof fields are laid out like Go's `SparseMerkleProof` and
+//! `CompactSparseMerkleProof`: sidenodes deepest first, a missing leaf or
+//! sibling as `None` where Go has `nil`, and the same most significant bit
+//! first bitmask. Go has no wire format of its own, so `WireFormat` output is
+//! specific to this crate.
+//!
+
+use crate::hasher::Sha256Hasher;
+use crate::proof::{CompactSparseMerkleProof, SparseMerkleProof};
+use crate::store::MemoryStore;
+use crate::tree::SparseMerkleTree;
+
+/// A tree laid out like a Go `smt.NewSparseMerkleTree(nodes, values, sha256.New())`
+pub type CelestiaTree<S = MemoryStore> = SparseMerkleTree<S, Sha256Hasher>;
+
+pub type CelestiaProof = SparseMerkleProof<Sha256Hasher>;
+
+pub type CelestiaCompactProof = CompactSparseMerkleProof<Sha256Hasher>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::from_hex;
+    use crate::{verify_proof, HashValue, DEFAULT_VALUE};
+
+    /// From an independent model of the Go library for now; see the file's header
+    const VECTORS: &str = include_str!("../testdata/celestia_sha256.txt");
+
+    fn hash(hex: &str) -> HashValue {
+        hex.parse().unwrap()
+    }
+
+    fn optional(hex: &str) -> Option<Vec<u8>> {
+        match hex {
+            "-" => None,
+            _ => Some(from_hex::<32>(hex).unwrap()),
+        }
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut tree: CelestiaTree = Default::default();
+        let mut roots = 0;
+        let mut proofs = 0;
+
+        for line in VECTORS.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            match fields[..] {
+                [] | [""] => {}
+                [comment, ..] if comment.starts_with('#') => {}
+                ["update", key, value] => {
+                    assert!(tree.update(key.as_bytes(), value.as_bytes()).is_ok());
+                }
+                ["delete", key] => {
+                    assert!(tree.update(key.as_bytes(), DEFAULT_VALUE).is_ok());
+                }
+                ["root", root] => {
+                    assert_eq!(tree.get_root(), hash(root), "{}", line);
+                    roots += 1;
+                }
+                ["proof", key, sidenodes, leaf_data, bitmask, num_sidenodes] => {
+                    let proof = tree.prove(key.as_bytes()).unwrap();
+                    let expected: Vec<HashValue> = match sidenodes {
+                        "-" => vec![],
+                        _ => sidenodes.split(',').map(hash).collect(),
+                    };
+                    assert_eq!(proof.sidenodes, expected, "{}", key);
+                    assert_eq!(proof.non_membership_leaf_data, optional(leaf_data));
+
+                    let compact = proof.compact().unwrap();
+                    assert_eq!(compact.bitmask, optional(bitmask).unwrap_or_default());
+                    assert_eq!(
+                        compact.num_sidenodes,
+                        num_sidenodes.parse::<usize>().unwrap()
+                    );
+                    assert_eq!(compact.decompact().unwrap(), proof);
+
+                    let value = tree.get(key.as_bytes()).unwrap_or_default();
+                    assert!(verify_proof(
+                        &proof,
+                        tree.get_root(),
+                        key.as_bytes(),
+                        &value
+                    ));
+                    proofs += 1;
+                }
+                _ => panic!("bad vector line: {}", line),
+            }
+        }
+        assert_eq!(roots, 24);
+        assert_eq!(proofs, 10);
+    }
+}
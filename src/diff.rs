@@ -0,0 +1,192 @@
+//!
+//! Diffs between roots
+//!
+
+use crate::error::Result;
+use crate::hasher::TreeHasher;
+use crate::store::TreeStore;
+use crate::tree::SparseMerkleTree;
+use crate::types::{HashValue, Node};
+
+/// A path that differs between two roots
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Change {
+    Added(HashValue),
+    Removed(HashValue),
+    Modified(HashValue),
+}
+
+impl Change {
+    pub fn path(&self) -> HashValue {
+        match self {
+            Change::Added(path) | Change::Removed(path) | Change::Modified(path) => *path,
+        }
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> SparseMerkleTree<S, H> {
+    /// The paths added, removed or modified going from `old_root` to
+    /// `new_root`, in path order. Subtrees with the same hash under both
+    /// roots are skipped without being loaded.
+    pub fn diff(&self, old_root: HashValue, new_root: HashValue) -> Result<Vec<Change>> {
+        let mut changes = Vec::new();
+        self.diff_subtree(old_root, new_root, &mut changes)?;
+        Ok(changes)
+    }
+
+    fn diff_subtree(
+        &self,
+        old: HashValue,
+        new: HashValue,
+        changes: &mut Vec<Change>,
+    ) -> Result<()> {
+        if old == new {
+            return Ok(());
+        }
+
+        if !old.is_placeholder() && !new.is_placeholder() {
+            if let (Node::Internal((old_left, old_right)), Node::Internal((new_left, new_right))) =
+                (self.get_node(old)?, self.get_node(new)?)
+            {
+                self.diff_subtree(old_left, new_left, changes)?;
+                return self.diff_subtree(old_right, new_right, changes);
+            }
+        }
+
+        // At least one side is a leaf or empty, so its subtree is small
+        // enough to compare leaf by leaf
+        let mut old_leaves = Vec::new();
+        let mut new_leaves = Vec::new();
+        self.collect_leaves(old, &mut old_leaves)?;
+        self.collect_leaves(new, &mut new_leaves)?;
+
+        let mut old_leaves = old_leaves.into_iter().peekable();
+        let mut new_leaves = new_leaves.into_iter().peekable();
+        loop {
+            let change = match (old_leaves.peek(), new_leaves.peek()) {
+                (None, None) => break,
+                (Some((old_path, _)), Some((new_path, _))) if old_path == new_path => {
+                    let (path, old_value) = old_leaves.next().unwrap();
+                    let (_, new_value) = new_leaves.next().unwrap();
+                    if old_value == new_value {
+                        continue;
+                    }
+                    Change::Modified(path)
+                }
+                (Some((old_path, _)), Some((new_path, _))) if new_path < old_path => {
+                    Change::Added(new_leaves.next().unwrap().0)
+                }
+                (Some(_), _) => Change::Removed(old_leaves.next().unwrap().0),
+                (None, Some(_)) => Change::Added(new_leaves.next().unwrap().0),
+            };
+            changes.push(change);
+        }
+        Ok(())
+    }
+
+    /// Append the `(path, value hash)` of every leaf under `hash`, in path order
+    fn collect_leaves(
+        &self,
+        hash: HashValue,
+        leaves: &mut Vec<(HashValue, HashValue)>,
+    ) -> Result<()> {
+        if hash.is_placeholder() {
+            return Ok(());
+        }
+        match self.get_node(hash)? {
+            Node::Leaf(leaf) => leaves.push(leaf),
+            Node::Internal((left, right)) => {
+                self.collect_leaves(left, leaves)?;
+                self.collect_leaves(right, leaves)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::TestStore;
+    use crate::DEFAULT_VALUE;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn test_diff() {
+        let mut tree = SparseMerkleTree::new(None);
+        let empty = tree.get_root();
+        assert!(tree.diff(empty, empty).unwrap().is_empty());
+
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let old = tree.get_root();
+
+        assert!(tree.update(&[1], DEFAULT_VALUE).is_ok());
+        assert!(tree.update(&[2], b"two").is_ok());
+        assert!(tree.update(&[100], b"hundred").is_ok());
+        // same value, so not a change
+        assert!(tree.update(&[3], &[3]).is_ok());
+        let new = tree.get_root();
+
+        let path = |i: u8| HashValue::digest_of(&[i]);
+        let mut expected = vec![
+            Change::Removed(path(1)),
+            Change::Modified(path(2)),
+            Change::Added(path(100)),
+        ];
+        expected.sort_by_key(|c| c.path());
+        assert_eq!(tree.diff(old, new).unwrap(), expected);
+
+        // and back the other way
+        let reversed: Vec<Change> = expected
+            .iter()
+            .map(|c| match *c {
+                Change::Added(p) => Change::Removed(p),
+                Change::Removed(p) => Change::Added(p),
+                c => c,
+            })
+            .collect();
+        assert_eq!(tree.diff(new, old).unwrap(), reversed);
+
+        let all = tree.diff(empty, new).unwrap();
+        assert_eq!(all.len(), 50);
+        assert!(all.iter().all(|c| matches!(c, Change::Added(_))));
+        assert!(all.windows(2).all(|w| w[0].path() < w[1].path()));
+    }
+
+    #[test]
+    fn test_leaf_moved_down() {
+        // a leaf that gets pushed deeper by a new neighbour is unchanged
+        let mut tree = SparseMerkleTree::new(None);
+        assert!(tree.update(b"a", b"a1").is_ok());
+        let old = tree.get_root();
+        assert!(tree.update(b"b", b"b1").is_ok());
+        assert!(tree.update(b"c", b"c1").is_ok());
+        let new = tree.get_root();
+
+        let mut expected = vec![
+            Change::Added(HashValue::digest_of(b"b")),
+            Change::Added(HashValue::digest_of(b"c")),
+        ];
+        expected.sort_by_key(|c| c.path());
+        assert_eq!(tree.diff(old, new).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_diff_cost() {
+        let mut tree: SparseMerkleTree<_> =
+            SparseMerkleTree::with_store(TestStore::default(), None);
+        for i in 0..1000u16 {
+            assert!(tree.update(&i.to_be_bytes(), b"v").is_ok());
+        }
+        let old = tree.get_root();
+        assert!(tree.update(b"x", b"changed").is_ok());
+        let new = tree.get_root();
+
+        tree.store().node_reads.store(0, Ordering::Relaxed);
+        assert_eq!(tree.diff(old, new).unwrap().len(), 1);
+        // two paths of about log2(1000) nodes each, not the whole tree
+        assert!(tree.store().node_reads.load(Ordering::Relaxed) < 60);
+    }
+}
@@ -0,0 +1,545 @@
+//!
+//! Binary encoding
+//!
+//! Values that leave the process are written as a version byte followed by a
+//! body. Integers are big endian.
+//!
+//! - `HashValue`: the `N` hash bytes
+//! - `Node`: `tag (u8) | left or path (N bytes) | right or value hash (N bytes)`
+//! - `SparseMerkleProof`: the body of its compact form
+//! - `CompactSparseMerkleProof`: `num_sidenodes (u16) | flags (u8) | bitmask | sidenodes | leaf data? | sibling data?`
+//! - `SparseMerkleMultiProof`: `num_paths (u32) | per path: depth (u16), has_leaf (u8), leaf data? | num_sidenodes (u32) | sidenodes`
+//! - `TransitionWitness`: `num_nodes (u32) | nodes`
+//! - `Chunk`: `depth (u32) | path | num_sidenodes (u32) | sidenodes | num_nodes (u32) | nodes | num_values (u32) | per value: length (u32), bytes`
+//!
+//! Decoding rejects unknown versions, truncated input and trailing bytes.
+//!
+//! With the `serde` feature, `HashValue` serializes as a hex string in
+//! human-readable formats and as its bytes in binary ones. The other types
+//! serialize their versioned encoding the same way.
+//!
+
+use crate::error::{Result, SmtError};
+use crate::hasher::TreeHasher;
+use crate::proof::{CompactSparseMerkleProof, SparseMerkleMultiProof, SparseMerkleProof};
+use crate::sync::Chunk;
+use crate::transition::TransitionWitness;
+use crate::types::{EncodedNode, HashValue, Node};
+
+/// Version byte of the current encoding
+pub const ENCODING_VERSION: u8 = 1;
+
+/// Versioned binary encoding, of a type with `N` byte hashes
+pub trait WireFormat<const N: usize = 32>: Sized {
+    /// Append the body, without the version byte
+    fn encode_body(&self, out: &mut Vec<u8>);
+
+    /// Decode a body that must fill all of `raw`
+    fn decode_body(raw: &[u8]) -> Result<Self, N>;
+
+    fn to_wire(&self) -> Vec<u8> {
+        let mut out = vec![ENCODING_VERSION];
+        self.encode_body(&mut out);
+        out
+    }
+
+    fn from_wire(raw: &[u8]) -> Result<Self, N> {
+        match raw.split_first() {
+            Some((&ENCODING_VERSION, body)) => Self::decode_body(body),
+            Some(_) => Err(SmtError::InvalidEncoding("unknown version")),
+            None => Err(SmtError::InvalidEncoding("unexpected end of input")),
+        }
+    }
+}
+
+impl<const N: usize> WireFormat<N> for HashValue<N> {
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        out.extend(self.as_ref());
+    }
+
+    fn decode_body(raw: &[u8]) -> Result<Self, N> {
+        let mut reader = Reader::<N>(raw);
+        let hash = reader.take_hash()?;
+        reader.finish()?;
+        Ok(hash)
+    }
+}
+
+impl<const N: usize> WireFormat<N> for Node<N> {
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_bytes());
+    }
+
+    fn decode_body(raw: &[u8]) -> Result<Self, N> {
+        Node::decode(raw)
+    }
+}
+
+impl<H: TreeHasher<N>, const N: usize> WireFormat<N> for SparseMerkleProof<H, N> {
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        out.extend(self.compact_unchecked().to_bytes());
+    }
+
+    fn decode_body(raw: &[u8]) -> Result<Self, N> {
+        CompactSparseMerkleProof::from_bytes(raw)?.decompact()
+    }
+}
+
+impl<H: TreeHasher<N>, const N: usize> WireFormat<N> for CompactSparseMerkleProof<H, N> {
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_bytes());
+    }
+
+    fn decode_body(raw: &[u8]) -> Result<Self, N> {
+        Self::from_bytes(raw)
+    }
+}
+
+impl<H: TreeHasher<N>, const N: usize> WireFormat<N> for SparseMerkleMultiProof<H, N> {
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        // Each path is written with its leaf, so a missing one can't be skipped
+        assert_eq!(
+            self.depths.len(),
+            self.leaf_data.len(),
+            "multiproof depths and leaf data differ in length"
+        );
+        out.extend(&(self.depths.len() as u32).to_be_bytes());
+        for (depth, leaf_data) in self.depths.iter().zip(&self.leaf_data) {
+            out.extend(&(*depth as u16).to_be_bytes());
+            match leaf_data {
+                Some(data) => {
+                    out.push(1);
+                    out.extend(data);
+                }
+                None => out.push(0),
+            }
+        }
+        out.extend(&(self.sidenodes.len() as u32).to_be_bytes());
+        for sidenode in &self.sidenodes {
+            out.extend(sidenode.as_ref());
+        }
+    }
+
+    fn decode_body(raw: &[u8]) -> Result<Self, N> {
+        let mut reader = Reader::<N>(raw);
+
+        let num_paths = reader.take_u32()?;
+        let mut depths = Vec::new();
+        let mut leaf_data = Vec::new();
+        for _ in 0..num_paths {
+            let depth = reader.take_u16()? as usize;
+            ensure!(
+                depth <= HashValue::<N>::DEPTH,
+                SmtError::InvalidProof("depth is too large")
+            );
+            depths.push(depth);
+            leaf_data.push(match reader.take(1)?[0] {
+                0 => None,
+                1 => Some(reader.take_node()?),
+                _ => return Err(SmtError::InvalidEncoding("unknown leaf flag")),
+            });
+        }
+
+        let num_sidenodes = reader.take_u32()? as usize;
+        ensure!(
+            reader.0.len() / N >= num_sidenodes,
+            SmtError::InvalidEncoding("unexpected end of input")
+        );
+        let mut sidenodes = Vec::with_capacity(num_sidenodes);
+        for _ in 0..num_sidenodes {
+            sidenodes.push(reader.take_hash()?);
+        }
+        reader.finish()?;
+
+        Ok(Self::new(depths, leaf_data, sidenodes))
+    }
+}
+
+impl WireFormat for TransitionWitness {
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_bytes());
+    }
+
+    fn decode_body(raw: &[u8]) -> Result<Self> {
+        Self::from_bytes(raw)
+    }
+}
+
+impl WireFormat for Chunk {
+    fn encode_body(&self, out: &mut Vec<u8>) {
+        out.extend(self.to_bytes());
+    }
+
+    fn decode_body(raw: &[u8]) -> Result<Self> {
+        Self::from_bytes(raw)
+    }
+}
+
+/// Reads fixed size pieces off the front of a byte slice, for a type with
+/// `N` byte hashes
+pub(crate) struct Reader<'a, const N: usize = 32>(pub(crate) &'a [u8]);
+
+impl<'a, const N: usize> Reader<'a, N> {
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], N> {
+        if self.0.len() < len {
+            return Err(SmtError::InvalidEncoding("unexpected end of input"));
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn take_u16(&mut self) -> Result<u16, N> {
+        let raw = self.take(2)?;
+        Ok(u16::from_be_bytes([raw[0], raw[1]]))
+    }
+
+    pub(crate) fn take_u32(&mut self) -> Result<u32, N> {
+        let raw = self.take(4)?;
+        Ok(u32::from_be_bytes([raw[0], raw[1], raw[2], raw[3]]))
+    }
+
+    pub(crate) fn take_hash(&mut self) -> Result<HashValue<N>, N> {
+        let mut hash = [0u8; N];
+        hash.copy_from_slice(self.take(N)?);
+        Ok(HashValue::new(hash))
+    }
+
+    pub(crate) fn take_node(&mut self) -> Result<EncodedNode, N> {
+        Ok(self.take(Node::<N>::ENCODED_LENGTH)?.to_vec())
+    }
+
+    /// Fail if anything is left
+    pub(crate) fn finish(&self) -> Result<(), N> {
+        ensure!(
+            self.0.is_empty(),
+            SmtError::InvalidEncoding("trailing bytes")
+        );
+        Ok(())
+    }
+}
+
+pub(crate) fn from_hex<const N: usize>(hex: &str) -> Result<Vec<u8>, N> {
+    fn digit<const N: usize>(c: u8) -> Result<u8, N> {
+        match c {
+            b'0'..=b'9' => Ok(c - b'0'),
+            b'a'..=b'f' => Ok(c - b'a' + 10),
+            b'A'..=b'F' => Ok(c - b'A' + 10),
+            _ => Err(SmtError::InvalidEncoding("invalid hex digit")),
+        }
+    }
+
+    let hex = hex.as_bytes();
+    ensure!(
+        hex.len().is_multiple_of(2),
+        SmtError::InvalidEncoding("odd number of hex digits")
+    );
+    hex.chunks(2)
+        .map(|pair| Ok(digit::<N>(pair[0])? << 4 | digit::<N>(pair[1])?))
+        .collect()
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::*;
+    use serde::de::{Error, SeqAccess, Visitor};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::fmt;
+
+    fn serialize_bytes<S: Serializer>(
+        bytes: &[u8],
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            serializer.serialize_str(&hex)
+        } else {
+            serializer.serialize_bytes(bytes)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a hex string or bytes")
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+            from_hex::<32>(v).map_err(E::custom)
+        }
+
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> std::result::Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> std::result::Result<Self::Value, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+
+    fn deserialize_bytes<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> std::result::Result<Vec<u8>, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_str(BytesVisitor)
+        } else {
+            deserializer.deserialize_bytes(BytesVisitor)
+        }
+    }
+
+    impl<const N: usize> Serialize for HashValue<N> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            serialize_bytes(self.as_ref(), serializer)
+        }
+    }
+
+    impl<'de, const N: usize> Deserialize<'de> for HashValue<N> {
+        fn deserialize<D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> std::result::Result<Self, D::Error> {
+            let bytes = deserialize_bytes(deserializer)?;
+            HashValue::decode_body(&bytes).map_err(D::Error::custom)
+        }
+    }
+
+    /// Serialize the versioned encoding of a `WireFormat` type
+    macro_rules! wire_serde {
+        ($ty:ty $(, $h:ident)?) => {
+            impl<$($h: TreeHasher<N>,)? const N: usize> Serialize for $ty {
+                fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+                    serialize_bytes(&self.to_wire(), serializer)
+                }
+            }
+
+            impl<'de, $($h: TreeHasher<N>,)? const N: usize> Deserialize<'de> for $ty {
+                fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+                    let bytes = deserialize_bytes(deserializer)?;
+                    Self::from_wire(&bytes).map_err(D::Error::custom)
+                }
+            }
+        };
+    }
+
+    wire_serde!(Node<N>);
+    wire_serde!(SparseMerkleProof<H, N>, H);
+    wire_serde!(CompactSparseMerkleProof<H, N>, H);
+    wire_serde!(SparseMerkleMultiProof<H, N>, H);
+
+    impl Serialize for TransitionWitness {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            serialize_bytes(&self.to_wire(), serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for TransitionWitness {
+        fn deserialize<D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> std::result::Result<Self, D::Error> {
+            let bytes = deserialize_bytes(deserializer)?;
+            Self::from_wire(&bytes).map_err(D::Error::custom)
+        }
+    }
+
+    impl Serialize for Chunk {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            serialize_bytes(&self.to_wire(), serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Chunk {
+        fn deserialize<D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> std::result::Result<Self, D::Error> {
+            let bytes = deserialize_bytes(deserializer)?;
+            Self::from_wire(&bytes).map_err(D::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::build_tree;
+    use crate::{Blake2bHasher, Blake2sHasher, MemoryStore, SparseMerkleTree, DEFAULT_VALUE};
+    use rand::{Rng, RngCore};
+
+    type Proof = SparseMerkleProof<Blake2sHasher>;
+    type Compact = CompactSparseMerkleProof<Blake2sHasher>;
+    type Multi = SparseMerkleMultiProof<Blake2sHasher>;
+
+    /// Decode `raw` as every type; none of them may panic
+    fn decode_all(raw: &[u8]) {
+        let _ = HashValue::<32>::from_wire(raw);
+        let _ = Node::<32>::from_wire(raw);
+        let _ = Proof::from_wire(raw);
+        let _ = Compact::from_wire(raw);
+        let _ = Multi::from_wire(raw);
+        let _ = TransitionWitness::from_wire(raw);
+        let _ = Chunk::from_wire(raw);
+    }
+
+    fn round_trip<T: WireFormat<N> + PartialEq + std::fmt::Debug, const N: usize>(
+        value: &T,
+    ) -> Vec<u8> {
+        let raw = value.to_wire();
+        assert_eq!(raw[0], ENCODING_VERSION);
+        assert_eq!(&T::from_wire(&raw).unwrap(), value);
+
+        // truncated, extended and unversioned input is rejected
+        for len in 0..raw.len() {
+            assert!(T::from_wire(&raw[..len]).is_err());
+        }
+        let mut long = raw.clone();
+        long.push(0);
+        assert!(T::from_wire(&long).is_err());
+        let mut other_version = raw.clone();
+        other_version[0] = ENCODING_VERSION + 1;
+        assert!(T::from_wire(&other_version).is_err());
+        raw
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let mut tree = build_tree(40);
+        let root = tree.get_root();
+        let node = Node::new_internal(root, HashValue::digest_of(b"x"));
+
+        let mut encodings = vec![round_trip(&root), round_trip(&node)];
+        for key in [[3u8], [200u8]] {
+            let proof = tree.prove_updatable(&key).unwrap();
+            encodings.push(round_trip(&proof));
+            encodings.push(round_trip(&proof.compact().unwrap()));
+        }
+        let multi = tree.prove_many(&[[1u8], [2], [250]]).unwrap();
+        encodings.push(round_trip(&multi));
+        let (_, witness) = tree
+            .prove_transition(root, vec![([1u8], DEFAULT_VALUE), ([99], b"x")])
+            .unwrap();
+        encodings.push(round_trip(&witness));
+        for chunk in tree.export_chunks(root, 8).unwrap() {
+            encodings.push(round_trip(&chunk));
+        }
+
+        // decoding gives back a proof that still verifies
+        let raw = tree.prove(&[5]).unwrap().to_wire();
+        assert!(crate::verify_proof(
+            &Proof::from_wire(&raw).unwrap(),
+            root,
+            &[5],
+            &[5]
+        ));
+        assert!(tree.update(&[5], b"other").is_ok());
+
+        // every type survives every other type's bytes, and random damage
+        let mut rng = rand::thread_rng();
+        for raw in &encodings {
+            decode_all(raw);
+            for _ in 0..200 {
+                let mut damaged = raw.clone();
+                let at = rng.gen_range(0..damaged.len());
+                damaged[at] = rng.gen();
+                decode_all(&damaged);
+                decode_all(&damaged[..rng.gen_range(0..=damaged.len())]);
+            }
+        }
+        for _ in 0..1000 {
+            let mut raw = vec![0u8; rng.gen_range(0..300)];
+            rng.fill_bytes(&mut raw);
+            if !raw.is_empty() {
+                raw[0] = ENCODING_VERSION;
+            }
+            decode_all(&raw);
+        }
+        // huge counts with no data behind them
+        decode_all(&[ENCODING_VERSION, 0xff, 0xff, 0xff, 0xff]);
+        decode_all(&[ENCODING_VERSION, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff]);
+    }
+
+    #[test]
+    #[should_panic(expected = "differ in length")]
+    fn test_mismatched_multiproof() {
+        let mut multi = build_tree(40).prove_many(&[[1u8], [2]]).unwrap();
+        multi.leaf_data.pop();
+        multi.to_wire();
+    }
+
+    #[test]
+    fn test_narrow_round_trips() {
+        let mut tree: SparseMerkleTree<MemoryStore<20>, Blake2bHasher, 20> = Default::default();
+        for i in 0..20u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let root = tree.get_root();
+        assert_eq!(round_trip(&root).len(), 21);
+
+        let proof = tree.prove_updatable(&[3]).unwrap();
+        let raw = round_trip(&proof);
+        round_trip(&proof.compact().unwrap());
+        round_trip(&tree.prove_many(&[[1u8], [2], [250]]).unwrap());
+
+        // a proof for one width doesn't decode as another
+        assert!(SparseMerkleProof::<Blake2bHasher, 20>::from_wire(&raw).is_ok());
+        assert!(SparseMerkleProof::<Blake2bHasher, 32>::from_wire(&raw).is_err());
+    }
+
+    #[test]
+    fn test_hex() {
+        let hash = HashValue::digest_of(b"a");
+        let hex = hash.to_string();
+        assert_eq!(hex.len(), 64);
+        assert_eq!(hex, format!("{:x}", hash));
+        assert_eq!(hex.parse::<HashValue>().unwrap(), hash);
+        assert_eq!(hex.to_uppercase().parse::<HashValue>().unwrap(), hash);
+
+        assert!("".parse::<HashValue>().is_err());
+        assert!(hex[1..].parse::<HashValue>().is_err());
+        assert!(format!("{}00", hex).parse::<HashValue>().is_err());
+        assert!(hex.replace(&hex[..1], "g").parse::<HashValue>().is_err());
+        assert!("é".repeat(32).parse::<HashValue>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let tree = build_tree(40);
+        let root = tree.get_root();
+
+        let json = serde_json::to_string(&root).unwrap();
+        assert_eq!(json, format!("\"{}\"", root));
+        assert_eq!(serde_json::from_str::<HashValue>(&json).unwrap(), root);
+        let bin = bincode::serialize(&root).unwrap();
+        assert_eq!(&bin[8..], root.as_ref());
+        assert_eq!(bincode::deserialize::<HashValue>(&bin).unwrap(), root);
+
+        let proof = tree.prove_updatable(&[7]).unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(serde_json::from_str::<Proof>(&json).unwrap(), proof);
+        let bin = bincode::serialize(&proof).unwrap();
+        assert_eq!(bincode::deserialize::<Proof>(&bin).unwrap(), proof);
+
+        let multi = tree.prove_many(&[[1u8], [2]]).unwrap();
+        let json = serde_json::to_string(&multi).unwrap();
+        assert_eq!(serde_json::from_str::<Multi>(&json).unwrap(), multi);
+
+        let chunk = tree.export_chunks(root, 8).unwrap().remove(0);
+        let json = serde_json::to_string(&chunk).unwrap();
+        assert_eq!(serde_json::from_str::<Chunk>(&json).unwrap(), chunk);
+        let bin = bincode::serialize(&chunk).unwrap();
+        assert_eq!(bincode::deserialize::<Chunk>(&bin).unwrap(), chunk);
+
+        assert!(serde_json::from_str::<HashValue>("\"00\"").is_err());
+        assert!(serde_json::from_str::<HashValue>("\"zz\"").is_err());
+        assert!(serde_json::from_str::<Proof>("\"0100\"").is_err());
+        assert!(serde_json::from_str::<Node>("[1, 2, 3]").is_err());
+    }
+}
@@ -0,0 +1,76 @@
+//!
+//! Errors
+//!
+
+use crate::types::HashValue;
+use std::fmt;
+
+/// `N` is the width of the hashes errors carry, as on `SmtError`
+pub type Result<T, const N: usize = 32> = std::result::Result<T, SmtError<N>>;
+
+/// Return `$err` unless `$cond` holds
+macro_rules! ensure {
+    ($cond:expr, $err:expr) => {
+        if !$cond {
+            return Err($err);
+        }
+    };
+}
+
+/// Errors from a tree, its stores and its proofs. `N` is the width of the
+/// tree's hashes, so the hashes carried here match the tree's own.
+#[derive(Debug)]
+pub enum SmtError<const N: usize = 32> {
+    /// A node the tree points to isn't in the store
+    MissingNode(HashValue<N>),
+    /// The value for a leaf's path isn't in the store, or doesn't match the leaf
+    MissingValue(HashValue<N>),
+    /// Node bytes couldn't be decoded
+    CorruptNode,
+    /// Found a leaf where an internal node was expected, or the other way around
+    UnexpectedNodeKind,
+    /// The key isn't in the tree
+    KeyNotFound,
+    /// The path isn't covered by any branch of a partial tree
+    PathNotCovered(HashValue<N>),
+    /// A proof, or its encoding, failed validation
+    InvalidProof(&'static str),
+    /// Encoded bytes couldn't be decoded
+    InvalidEncoding(&'static str),
+    /// A state sync chunk failed validation
+    InvalidChunk(&'static str),
+    /// The store backend failed
+    Storage(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl<const N: usize> fmt::Display for SmtError<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtError::MissingNode(key) => write!(f, "missing node {:x}", key),
+            SmtError::MissingValue(path) => write!(f, "missing value for path {:x}", path),
+            SmtError::CorruptNode => write!(f, "corrupt node"),
+            SmtError::UnexpectedNodeKind => write!(f, "unexpected node kind"),
+            SmtError::KeyNotFound => write!(f, "key not found"),
+            SmtError::PathNotCovered(path) => write!(f, "path {:x} is not covered", path),
+            SmtError::InvalidProof(reason) => write!(f, "invalid proof: {}", reason),
+            SmtError::InvalidEncoding(reason) => write!(f, "invalid encoding: {}", reason),
+            SmtError::InvalidChunk(reason) => write!(f, "invalid chunk: {}", reason),
+            SmtError::Storage(e) => write!(f, "storage error: {}", e),
+        }
+    }
+}
+
+impl<const N: usize> std::error::Error for SmtError<N> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SmtError::Storage(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl<const N: usize> From<std::io::Error> for SmtError<N> {
+    fn from(e: std::io::Error) -> Self {
+        SmtError::Storage(Box::new(e))
+    }
+}
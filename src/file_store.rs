@@ -0,0 +1,460 @@
+//!
+//! Append-only file store
+//!
+//! Every change is appended to a log file as a record:
+//!
+//! `crc32 (u32) | length (u32) | op (u8) | key (N bytes) | data`
+//!
+//! Integers are big endian, and the checksum covers the length and everything
+//! after it. The in-memory index is rebuilt by replaying the log on open. Only
+//! changes up to the last root record count as committed: a torn or corrupt
+//! record, and anything after the last root record, is truncated away.
+//!
+//! A failed append is cut back off the log straight away, so later records
+//! stay where the index expects them. If that fails too, the store refuses
+//! further writes until it is reopened.
+//!
+//! The hash width isn't stored, so a log must be opened at the width it was
+//! written with. Opening it at another width fails instead of truncating it.
+//!
+
+use crate::error::{Result, SmtError};
+use crate::store::TreeStore;
+use crate::types::{HashValue, Node};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+const SET_NODE: u8 = 1;
+const DELETE_NODE: u8 = 2;
+const SET_VALUE: u8 = 3;
+const DELETE_VALUE: u8 = 4;
+const SET_ROOT: u8 = 5;
+
+const HEADER_LENGTH: u64 = 8;
+/// Upper bound on a record, so a corrupt length can't make us allocate gigabytes
+const MAX_RECORD_LENGTH: u32 = 1 << 30;
+
+/// Where the data of a record lives in the log
+#[derive(Clone, Copy)]
+struct Entry {
+    offset: u64,
+    len: u32,
+}
+
+/// A persistent `TreeStore` backed by an append-only log file, for a tree
+/// with `N` byte hashes
+pub struct FileStore<const N: usize = 32> {
+    writer: File,
+    reader: Mutex<File>,
+    end: u64,
+    /// Set when a failed write couldn't be undone
+    failed: bool,
+    nodes: HashMap<HashValue<N>, Entry>,
+    values: HashMap<HashValue<N>, Entry>,
+    root: Option<HashValue<N>>,
+}
+
+impl<const N: usize> FileStore<N> {
+    const PAYLOAD_PREFIX_LENGTH: u64 = 1 + N as u64;
+
+    /// Open the log at `path`, creating it if needed, and recover the last committed state
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, N> {
+        let path = path.as_ref();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+
+        // Separate handles, since clones of a `File` share one cursor
+        let mut store = Self {
+            writer: OpenOptions::new().append(true).open(path)?,
+            reader: Mutex::new(File::open(path)?),
+            end: 0,
+            failed: false,
+            nodes: HashMap::new(),
+            values: HashMap::new(),
+            root: None,
+        };
+        store.replay(&mut file)?;
+
+        // Drop the torn or uncommitted tail
+        if file.metadata()?.len() != store.end {
+            file.set_len(store.end)?;
+            file.sync_all()?;
+        }
+        Ok(store)
+    }
+
+    fn replay(&mut self, file: &mut File) -> Result<(), N> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut offset = 0u64;
+        let mut pending = Vec::new();
+
+        loop {
+            let mut header = [0u8; HEADER_LENGTH as usize];
+            if !read_record_part(&mut reader, &mut header)? {
+                break;
+            }
+            let crc = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+            let len = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+            if len > MAX_RECORD_LENGTH {
+                break;
+            }
+
+            let mut payload = vec![0u8; len as usize];
+            if !read_record_part(&mut reader, &mut payload)? {
+                break;
+            }
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(&header[4..]);
+            hasher.update(&payload);
+            if hasher.finalize() != crc {
+                break;
+            }
+
+            // An intact record that doesn't fit the layout was written at another width
+            ensure!(
+                len as u64 >= Self::PAYLOAD_PREFIX_LENGTH,
+                SmtError::Storage("log was written with another hash width".into())
+            );
+            let op = payload[0];
+            let mut key = [0u8; N];
+            key.copy_from_slice(&payload[1..Self::PAYLOAD_PREFIX_LENGTH as usize]);
+            let key = HashValue::new(key);
+            let entry = Entry {
+                offset: offset + HEADER_LENGTH + Self::PAYLOAD_PREFIX_LENGTH,
+                len: len - Self::PAYLOAD_PREFIX_LENGTH as u32,
+            };
+            offset += HEADER_LENGTH + len as u64;
+
+            match op {
+                SET_ROOT => {
+                    ensure!(
+                        entry.len == 0,
+                        SmtError::Storage("log was written with another hash width".into())
+                    );
+                    for (op, key, entry) in pending.drain(..) {
+                        self.apply(op, key, entry);
+                    }
+                    self.root = Some(key);
+                    self.end = offset;
+                }
+                SET_NODE | DELETE_NODE | SET_VALUE | DELETE_VALUE => pending.push((op, key, entry)),
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, op: u8, key: HashValue<N>, entry: Entry) {
+        match op {
+            SET_NODE => {
+                self.nodes.insert(key, entry);
+            }
+            DELETE_NODE => {
+                self.nodes.remove(&key);
+            }
+            SET_VALUE => {
+                self.values.insert(key, entry);
+            }
+            DELETE_VALUE => {
+                self.values.remove(&key);
+            }
+            _ => {}
+        }
+    }
+
+    fn append(&mut self, op: u8, key: &HashValue<N>, data: &[u8]) -> Result<Entry, N> {
+        ensure!(
+            !self.failed,
+            SmtError::Storage("file store is read-only after a failed write".into())
+        );
+        let len = Self::PAYLOAD_PREFIX_LENGTH as usize + data.len();
+        if len > MAX_RECORD_LENGTH as usize {
+            return Err(SmtError::Storage("record too large".into()));
+        }
+        let len = len as u32;
+
+        let mut record = Vec::with_capacity(HEADER_LENGTH as usize + len as usize);
+        record.extend(&[0u8; 4]);
+        record.extend(&len.to_be_bytes());
+        record.push(op);
+        record.extend(key.as_ref());
+        record.extend(data);
+        let crc = crc32fast::hash(&record[4..]);
+        record[..4].copy_from_slice(&crc.to_be_bytes());
+
+        if let Err(e) = self.writer.write_all(&record) {
+            // Drop whatever part of the record made it in
+            if self.writer.set_len(self.end).is_err() {
+                self.failed = true;
+            }
+            return Err(e.into());
+        }
+        let entry = Entry {
+            offset: self.end + HEADER_LENGTH + Self::PAYLOAD_PREFIX_LENGTH,
+            len: data.len() as u32,
+        };
+        self.end += record.len() as u64;
+        Ok(entry)
+    }
+
+    fn read(&self, entry: Entry) -> Result<Vec<u8>, N> {
+        let mut reader = self
+            .reader
+            .lock()
+            .map_err(|_| SmtError::Storage("file store lock poisoned".into()))?;
+        reader.seek(SeekFrom::Start(entry.offset))?;
+        let mut data = vec![0u8; entry.len as usize];
+        reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+/// Fill `buf`, returning false if the log ends first
+fn read_record_part<R: Read, const N: usize>(reader: &mut R, buf: &mut [u8]) -> Result<bool, N> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl<const N: usize> TreeStore<N> for FileStore<N> {
+    fn get_root(&self) -> Result<Option<HashValue<N>>, N> {
+        Ok(self.root)
+    }
+
+    fn set_root(&mut self, root: HashValue<N>) -> Result<(), N> {
+        self.append(SET_ROOT, &root, &[])?;
+        self.writer.sync_data()?;
+        self.root = Some(root);
+        Ok(())
+    }
+
+    fn get_node(&self, key: HashValue<N>) -> Result<Option<Node<N>>, N> {
+        match self.nodes.get(&key) {
+            Some(entry) => {
+                let raw = self.read(*entry)?;
+                Node::decode(&raw).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn set_node(&mut self, key: HashValue<N>, node: Node<N>) -> Result<(), N> {
+        if !self.nodes.contains_key(&key) {
+            let entry = self.append(SET_NODE, &key, &node.to_bytes())?;
+            self.nodes.insert(key, entry);
+        }
+        Ok(())
+    }
+
+    fn delete_node(&mut self, key: &HashValue<N>) -> Result<(), N> {
+        if self.nodes.remove(key).is_some() {
+            self.append(DELETE_NODE, key, &[])?;
+        }
+        Ok(())
+    }
+
+    fn get_value(&self, key: HashValue<N>) -> Result<Option<Vec<u8>>, N> {
+        match self.values.get(&key) {
+            Some(entry) => self.read(*entry).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn set_value(&mut self, key: HashValue<N>, value: &[u8]) -> Result<(), N> {
+        let entry = self.append(SET_VALUE, &key, value)?;
+        self.values.insert(key, entry);
+        Ok(())
+    }
+
+    fn delete_value(&mut self, key: &HashValue<N>) -> Result<(), N> {
+        if self.values.remove(key).is_some() {
+            self.append(DELETE_VALUE, key, &[])?;
+        }
+        Ok(())
+    }
+
+    fn node_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        Ok(self.nodes.keys().copied().collect())
+    }
+
+    fn value_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        Ok(self.values.keys().copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Blake2bHasher, SparseMerkleTree, DEFAULT_VALUE};
+    use std::path::PathBuf;
+
+    fn temp_log(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("smt-{}-{}.log", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn open_tree(path: &Path) -> SparseMerkleTree<FileStore> {
+        SparseMerkleTree::load(FileStore::open(path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_reopen() {
+        let path = temp_log("reopen");
+
+        let mut tree = open_tree(&path);
+        assert!(tree.get_root().is_placeholder());
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i, i]).is_ok());
+        }
+        assert!(tree.update(&[7], DEFAULT_VALUE).is_ok());
+        assert!(tree.commit().is_ok());
+        let root = tree.get_root();
+        drop(tree);
+
+        let mut tree = open_tree(&path);
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.get(&[3]).unwrap(), vec![3, 3]);
+        assert!(matches!(tree.get(&[7]), Err(SmtError::KeyNotFound)));
+
+        // not committed, so it's gone after reopening
+        assert!(tree.update(&[100], b"lost").is_ok());
+        assert_eq!(tree.get(&[100]).unwrap(), b"lost");
+        drop(tree);
+
+        let tree = open_tree(&path);
+        assert_eq!(tree.get_root(), root);
+        assert!(matches!(tree.get(&[100]), Err(SmtError::KeyNotFound)));
+        assert_eq!(tree.get(&[49]).unwrap(), vec![49, 49]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_torn_record() {
+        let path = temp_log("torn");
+
+        let mut tree = open_tree(&path);
+        assert!(tree.update(b"a", b"a1").is_ok());
+        assert!(tree.update(b"b", b"b1").is_ok());
+        assert!(tree.commit().is_ok());
+        let root = tree.get_root();
+        drop(tree);
+        let committed_len = std::fs::metadata(&path).unwrap().len();
+
+        // half a record at the end, as if we crashed in the middle of a write
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 1, 0, 0, 0, 40, SET_VALUE, 1, 2])
+            .unwrap();
+        drop(file);
+
+        let mut tree = open_tree(&path);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), committed_len);
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.get(b"a").unwrap(), b"a1");
+
+        // a full record with a bad checksum is dropped too
+        assert!(tree.update(b"c", b"c1").is_ok());
+        assert!(tree.commit().is_ok());
+        let root = tree.get_root();
+        let len = std::fs::metadata(&path).unwrap().len();
+        drop(tree);
+
+        let mut data = std::fs::read(&path).unwrap();
+        let mut record = [0u8; 41];
+        record[7] = 33;
+        record[8] = SET_ROOT;
+        data.extend(&record);
+        std::fs::write(&path, &data).unwrap();
+
+        let tree = open_tree(&path);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.get(b"c").unwrap(), b"c1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_failed_write() {
+        let path = temp_log("failed");
+
+        let mut tree = open_tree(&path);
+        assert!(tree.update(b"a", b"a1").is_ok());
+        assert!(tree.commit().is_ok());
+        let root = tree.get_root();
+
+        // a handle that can't write or truncate, so the store gives up on writes
+        let mut store = tree.into_store();
+        store.writer = File::open(&path).unwrap();
+        let mut tree: SparseMerkleTree<_> = SparseMerkleTree::with_store(store, Some(root));
+        assert!(matches!(
+            tree.update(b"b", b"b1"),
+            Err(SmtError::Storage(_))
+        ));
+        assert!(matches!(tree.commit(), Err(SmtError::Storage(_))));
+        assert!(tree.store().failed);
+        drop(tree);
+
+        let tree = open_tree(&path);
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.get(b"a").unwrap(), b"a1");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_widths() {
+        let path = temp_log("narrow");
+        let mut tree: SparseMerkleTree<FileStore<20>, Blake2bHasher, 20> =
+            SparseMerkleTree::load(FileStore::open(&path).unwrap()).unwrap();
+        for i in 0..20u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        assert!(tree.commit().is_ok());
+        let root = tree.get_root();
+        drop(tree);
+
+        let tree: SparseMerkleTree<FileStore<20>, Blake2bHasher, 20> =
+            SparseMerkleTree::load(FileStore::open(&path).unwrap()).unwrap();
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.get(&[7]).unwrap(), vec![7]);
+        drop(tree);
+
+        // a log opened at the wrong width is refused, not truncated
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert!(matches!(
+            FileStore::<32>::open(&path),
+            Err(SmtError::Storage(_))
+        ));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        assert_eq!(
+            FileStore::<20>::open(&path).unwrap().get_root().unwrap(),
+            Some(root)
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        let path = temp_log("wide");
+        let mut tree = open_tree(&path);
+        assert!(tree.update(b"a", b"a1").is_ok());
+        assert!(tree.commit().is_ok());
+        drop(tree);
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert!(matches!(
+            FileStore::<20>::open(&path),
+            Err(SmtError::Storage(_))
+        ));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+//!
+//! Garbage collection
+//!
+//! Mark and sweep over a store shared by many roots. A `GarbageCollector`
+//! runs in bounded steps, so a large store can be swept between other work.
+//!
+
+use std::collections::HashSet;
+
+use crate::error::Result;
+use crate::hasher::TreeHasher;
+use crate::store::TreeStore;
+use crate::tree::SparseMerkleTree;
+use crate::types::{HashValue, Node};
+
+/// What a garbage collection pass freed
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct GcStats {
+    pub nodes_freed: u64,
+    pub values_freed: u64,
+    /// Encoded size of the freed nodes plus the length of the freed values
+    pub bytes_freed: u64,
+}
+
+/// An incremental mark-and-sweep pass, from `SparseMerkleTree::garbage_collector`.
+///
+/// The keys to sweep are listed when the pass starts, so nodes written while
+/// it runs are never freed. A write can also bring back a listed node though,
+/// such as a leaf set back to an earlier value, so every root still in use
+/// must be passed to `retain` before each step. `SparseMerkleTree::gc_step`
+/// does this for the tree's current root, and every step retains the store's
+/// pinned roots, such as those of live snapshots.
+pub struct GarbageCollector {
+    /// Nodes still to mark
+    stack: Vec<HashValue>,
+    /// Reachable nodes. Values are keyed by their leaf's hash, so this covers
+    /// the values to keep too.
+    marked: HashSet<HashValue>,
+    nodes: Vec<HashValue>,
+    values: Vec<HashValue>,
+    stats: GcStats,
+}
+
+impl GarbageCollector {
+    /// Start a pass over `store`, keeping everything reachable from `retained_roots`
+    pub fn new<S: TreeStore>(store: &S, retained_roots: &[HashValue]) -> Result<Self> {
+        Ok(Self {
+            stack: retained_roots.to_vec(),
+            marked: HashSet::new(),
+            nodes: store.node_keys()?,
+            values: store.value_keys()?,
+            stats: GcStats::default(),
+        })
+    }
+
+    /// Keep everything reachable from `root` too. It is marked before anything
+    /// else is swept, stopping at nodes already marked.
+    pub fn retain(&mut self, root: HashValue) {
+        self.stack.push(root);
+    }
+
+    /// Visit or delete up to `budget` nodes and values, returning true once the
+    /// pass is complete
+    pub fn step<S: TreeStore>(&mut self, store: &mut S, budget: usize) -> Result<bool> {
+        self.stack.extend(store.pinned_roots()?);
+        let mut budget = budget;
+        while budget > 0 {
+            budget -= 1;
+
+            if let Some(hash) = self.stack.pop() {
+                if hash.is_placeholder() || !self.marked.insert(hash) {
+                    continue;
+                }
+                match store.get_node(hash)? {
+                    Some(Node::Internal((left, right))) => {
+                        self.stack.push(right);
+                        self.stack.push(left);
+                    }
+                    // Already gone, and nothing under it can be found anyway
+                    Some(Node::Leaf(_)) | None => {}
+                }
+            } else if let Some(key) = self.nodes.pop() {
+                if self.marked.contains(&key) {
+                    continue;
+                }
+                if let Some(node) = store.get_node(key)? {
+                    self.stats.nodes_freed += 1;
+                    self.stats.bytes_freed += node.to_bytes().len() as u64;
+                    store.delete_node(&key)?;
+                }
+            } else if let Some(key) = self.values.pop() {
+                if self.marked.contains(&key) {
+                    continue;
+                }
+                if let Some(value) = store.get_value(key)? {
+                    self.stats.values_freed += 1;
+                    self.stats.bytes_freed += value.len() as u64;
+                    store.delete_value(&key)?;
+                }
+            } else {
+                return Ok(true);
+            }
+        }
+        Ok(self.is_done())
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.stack.is_empty() && self.nodes.is_empty() && self.values.is_empty()
+    }
+
+    /// What the pass has freed so far
+    pub fn stats(&self) -> GcStats {
+        self.stats
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> SparseMerkleTree<S, H> {
+    /// Start an incremental garbage collection pass. The current root is
+    /// always retained, along with `retained_roots`. Run it with `gc_step`.
+    pub fn garbage_collector(&self, retained_roots: &[HashValue]) -> Result<GarbageCollector> {
+        let mut roots = retained_roots.to_vec();
+        roots.push(self.get_root());
+        GarbageCollector::new(self.store(), &roots)
+    }
+
+    /// Run one step of `gc` on this tree's store, first retaining the current root
+    pub fn gc_step(&mut self, gc: &mut GarbageCollector, budget: usize) -> Result<bool> {
+        gc.retain(self.get_root());
+        gc.step(self.store_mut(), budget)
+    }
+
+    /// Delete every node and value not reachable from the current root or
+    /// `retained_roots`, in one go
+    pub fn collect_garbage(&mut self, retained_roots: &[HashValue]) -> Result<GcStats> {
+        let mut gc = self.garbage_collector(retained_roots)?;
+        while !self.gc_step(&mut gc, usize::MAX)? {}
+        Ok(gc.stats())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::{build_tree, TestStore};
+    use crate::SmtError;
+
+    fn node_count(tree: &SparseMerkleTree) -> usize {
+        tree.store().node_keys().unwrap().len()
+    }
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut tree = build_tree(50);
+        let old = build_tree(21).get_root();
+        let before = node_count(&tree);
+
+        let stats = tree.collect_garbage(&[old]).unwrap();
+        assert_eq!(node_count(&tree), before - stats.nodes_freed as usize);
+        assert!(stats.nodes_freed > 0);
+        assert_eq!(stats.values_freed, 0);
+        assert!(stats.bytes_freed >= stats.nodes_freed * 65);
+
+        // both retained roots still work, others are gone
+        for i in 0..50u8 {
+            assert_eq!(tree.get(&[i]).unwrap(), vec![i]);
+        }
+        for i in 0..=20u8 {
+            assert_eq!(tree.get_for_root(&[i], old).unwrap(), Some(vec![i]));
+        }
+        assert!(matches!(
+            tree.get_for_root(&[0], build_tree(11).get_root()),
+            Err(SmtError::MissingNode(_))
+        ));
+
+        // deleted keys lose their values
+        assert!(tree.update(&[7], b"").is_ok());
+        let stats = tree.collect_garbage(&[]).unwrap();
+        assert_eq!(stats.values_freed, 1);
+        assert_eq!(tree.store().value_keys().unwrap().len(), 49);
+        assert_eq!(tree.collect_garbage(&[]).unwrap(), GcStats::default());
+    }
+
+    #[test]
+    fn test_incremental() {
+        let mut tree = build_tree(40);
+        let mut expected = build_tree(40);
+        let old = build_tree(6).get_root();
+        let expected_stats = expected.collect_garbage(&[old]).unwrap();
+
+        let mut gc = tree.garbage_collector(&[old]).unwrap();
+        let mut steps = 0;
+        while !tree.gc_step(&mut gc, 10).unwrap() {
+            steps += 1;
+            // the writer keeps going, and its new nodes survive
+            if steps == 3 {
+                assert!(tree.update(&[200], b"late").is_ok());
+            }
+        }
+        assert!(steps > 10);
+        assert!(gc.is_done());
+        assert_eq!(gc.stats().nodes_freed, expected_stats.nodes_freed);
+        assert_eq!(tree.get(&[200]).unwrap(), b"late");
+        assert_eq!(tree.get(&[39]).unwrap(), vec![39]);
+
+        // setting a key back to an old value brings back nodes listed for sweeping
+        let mut tree = build_tree(8);
+        assert!(tree.update(&[1], b"v2").is_ok());
+        let mut gc = tree.garbage_collector(&[]).unwrap();
+        assert!(tree.update(&[1], &[1]).is_ok());
+        while !tree.gc_step(&mut gc, 5).unwrap() {}
+        assert!(gc.stats().nodes_freed > 0);
+        for i in 0..8u8 {
+            assert_eq!(tree.get(&[i]).unwrap(), vec![i]);
+        }
+    }
+
+    #[test]
+    fn test_unsupported_store() {
+        let mut tree: SparseMerkleTree<_> = SparseMerkleTree::with_store(
+            TestStore {
+                unlisted: true,
+                ..TestStore::default()
+            },
+            None,
+        );
+        assert!(tree.update(b"a", b"a").is_ok());
+        assert!(matches!(
+            tree.collect_garbage(&[]),
+            Err(SmtError::Storage(_))
+        ));
+    }
+}
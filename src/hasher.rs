@@ -0,0 +1,220 @@
+//!
+//! Hash functions for the tree
+//!
+
+use crate::types::HashValue;
+
+/// The hash function used for key paths, value hashes and node hashes, with
+/// an `N` byte output that sets the width of the tree's paths.
+///
+/// A tree, its `Node` hashes and its proofs must all use the same hasher.
+pub trait TreeHasher<const N: usize = 32> {
+    fn digest(data: &[u8]) -> HashValue<N>;
+
+    /// Hash the concatenation of `parts`. Hashers that can take their input
+    /// in pieces should override this, so nodes hash without a copy.
+    fn digest_parts(parts: &[&[u8]]) -> HashValue<N> {
+        Self::digest(&parts.concat())
+    }
+}
+
+/// Blake2s-256, the default hasher
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Blake2sHasher;
+
+impl TreeHasher for Blake2sHasher {
+    fn digest(data: &[u8]) -> HashValue {
+        Self::digest_parts(&[data])
+    }
+
+    fn digest_parts(parts: &[&[u8]]) -> HashValue {
+        use blake2::{Blake2s, Digest};
+
+        let mut hash = [0u8; 32];
+        let mut hasher = Blake2s::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hash.copy_from_slice(hasher.finalize().as_ref());
+        HashValue::new(hash)
+    }
+}
+
+/// Blake2b with an `N` byte output, for any width from 1 to 64 bytes. That
+/// covers 20 byte paths, the default 32 and the full 64 byte Blake2b-512.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Blake2bHasher;
+
+/// Fails to compile for a width Blake2b can't produce
+struct Blake2bWidth<const N: usize>;
+
+impl<const N: usize> Blake2bWidth<N> {
+    const N: usize = {
+        assert!(N >= 1 && N <= 64, "Blake2b outputs 1 to 64 bytes");
+        N
+    };
+}
+
+impl<const N: usize> TreeHasher<N> for Blake2bHasher {
+    fn digest(data: &[u8]) -> HashValue<N> {
+        Self::digest_parts(&[data])
+    }
+
+    fn digest_parts(parts: &[&[u8]]) -> HashValue<N> {
+        use blake2::digest::{Update, VariableOutput};
+        use blake2::VarBlake2b;
+
+        let mut hash = [0u8; N];
+        let mut hasher =
+            VarBlake2b::new(Blake2bWidth::<N>::N).expect("width checked at compile time");
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finalize_variable(|res| hash.copy_from_slice(res));
+        HashValue::new(hash)
+    }
+}
+
+#[cfg(feature = "sha256")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Sha256Hasher;
+
+#[cfg(feature = "sha256")]
+impl TreeHasher for Sha256Hasher {
+    fn digest(data: &[u8]) -> HashValue {
+        Self::digest_parts(&[data])
+    }
+
+    fn digest_parts(parts: &[&[u8]]) -> HashValue {
+        use sha2::{Digest, Sha256};
+
+        let mut hash = [0u8; 32];
+        let mut hasher = Sha256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hash.copy_from_slice(hasher.finalize().as_ref());
+        HashValue::new(hash)
+    }
+}
+
+/// Keccak-256 as used by Ethereum, not the standardized SHA3-256
+#[cfg(feature = "keccak")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Keccak256Hasher;
+
+#[cfg(feature = "keccak")]
+impl TreeHasher for Keccak256Hasher {
+    fn digest(data: &[u8]) -> HashValue {
+        Self::digest_parts(&[data])
+    }
+
+    fn digest_parts(parts: &[&[u8]]) -> HashValue {
+        use sha3::{Digest, Keccak256};
+
+        let mut hash = [0u8; 32];
+        let mut hasher = Keccak256::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        hash.copy_from_slice(hasher.finalize().as_ref());
+        HashValue::new(hash)
+    }
+}
+
+#[cfg(feature = "blake3")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Blake3Hasher;
+
+#[cfg(feature = "blake3")]
+impl TreeHasher for Blake3Hasher {
+    fn digest(data: &[u8]) -> HashValue {
+        HashValue::new(*blake3::hash(data).as_bytes())
+    }
+
+    fn digest_parts(parts: &[&[u8]]) -> HashValue {
+        let mut hasher = blake3::Hasher::new();
+        for part in parts {
+            hasher.update(part);
+        }
+        HashValue::new(*hasher.finalize().as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::types::Node;
+    use crate::{verify_proof, SparseMerkleTree, DEFAULT_VALUE};
+
+    fn check_digest<H: TreeHasher>(expected: &str) {
+        assert_eq!(format!("{:x}", H::digest(b"")), expected);
+        assert_eq!(H::digest_parts(&[b"ab", b"", b"c"]), H::digest(b"abc"));
+        let node = Node::new_internal(H::digest(b"l"), H::digest(b"r"));
+        assert_eq!(node.hash::<H>(), H::digest(&node.to_bytes()));
+    }
+
+    /// Build a small tree with `H` and check its proofs, returning the root
+    fn check_tree<H: TreeHasher>() -> HashValue {
+        let mut tree = SparseMerkleTree::<MemoryStore, H>::default();
+        for (k, v) in &[(b"a", b"a1"), (b"b", b"b1"), (b"c", b"c1")] {
+            assert!(tree.update(*k, *v).is_ok());
+        }
+        let root = tree.get_root();
+        assert_eq!(tree.get(b"b").unwrap(), b"b1");
+
+        let proof = tree.prove(b"b").unwrap();
+        assert!(verify_proof(&proof, root, b"b", b"b1"));
+        let proof = tree.prove(b"x").unwrap();
+        assert!(verify_proof(&proof, root, b"x", DEFAULT_VALUE));
+        root
+    }
+
+    #[test]
+    fn test_hashers() {
+        check_digest::<Blake2sHasher>(
+            "69217a3079908094e11121d042354a7c1f55b6482ca1a51e1b250dfd1ed0eef9",
+        );
+        check_digest::<Blake2bHasher>(
+            "0e5751c026e543b2e8ab2eb06099daa1d1e5df47778f7787faab45cdf12fe3a8",
+        );
+
+        let default_root = check_tree::<Blake2sHasher>();
+        assert_eq!(default_root, {
+            let mut tree = SparseMerkleTree::new(None);
+            for (k, v) in &[(b"a", b"a1"), (b"b", b"b1"), (b"c", b"c1")] {
+                assert!(tree.update(*k, *v).is_ok());
+            }
+            tree.get_root()
+        });
+        assert_ne!(check_tree::<Blake2bHasher>(), default_root);
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn test_sha256() {
+        check_digest::<Sha256Hasher>(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+        );
+        check_tree::<Sha256Hasher>();
+    }
+
+    #[cfg(feature = "keccak")]
+    #[test]
+    fn test_keccak() {
+        check_digest::<Keccak256Hasher>(
+            "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470",
+        );
+        check_tree::<Keccak256Hasher>();
+    }
+
+    #[cfg(feature = "blake3")]
+    #[test]
+    fn test_blake3() {
+        check_digest::<Blake3Hasher>(
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        );
+        check_tree::<Blake3Hasher>();
+    }
+}
@@ -0,0 +1,200 @@
+//!
+//! Leaf iteration
+//!
+
+use crate::error::Result;
+use crate::hasher::TreeHasher;
+use crate::store::TreeStore;
+use crate::tree::SparseMerkleTree;
+use crate::types::{HashValue, Node};
+
+/// A subtree still to be visited, with the smallest and largest path it can hold
+struct Subtree<const N: usize> {
+    hash: HashValue<N>,
+    depth: usize,
+    lo: HashValue<N>,
+    hi: HashValue<N>,
+}
+
+/// Iterator over the `(path, value)` pairs under a root, in path order.
+///
+/// Nodes are loaded from the store as the iterator reaches them. After an
+/// error the iterator is exhausted.
+pub struct Iter<'a, S: TreeStore<N>, H: TreeHasher<N>, const N: usize = 32> {
+    tree: &'a SparseMerkleTree<S, H, N>,
+    stack: Vec<Subtree<N>>,
+    start: HashValue<N>,
+    end: HashValue<N>,
+}
+
+impl<'a, S: TreeStore<N>, H: TreeHasher<N>, const N: usize> Iter<'a, S, H, N> {
+    pub(crate) fn new(
+        tree: &'a SparseMerkleTree<S, H, N>,
+        root: HashValue<N>,
+        start: HashValue<N>,
+        end: HashValue<N>,
+    ) -> Self {
+        let mut stack = Vec::new();
+        if !root.is_placeholder() && start <= end {
+            stack.push(Subtree {
+                hash: root,
+                depth: 0,
+                lo: HashValue::new([0u8; N]),
+                hi: HashValue::new([0xffu8; N]),
+            });
+        }
+        Self {
+            tree,
+            stack,
+            start,
+            end,
+        }
+    }
+
+    fn next_leaf(&mut self) -> Result<Option<(HashValue<N>, Vec<u8>)>, N> {
+        while let Some(subtree) = self.stack.pop() {
+            if subtree.hi < self.start || subtree.lo > self.end {
+                continue;
+            }
+
+            match self.tree.get_node(subtree.hash)? {
+                Node::Leaf((path, value_hash)) => {
+                    if path >= self.start && path <= self.end {
+                        let value = self.tree.get_value(path, value_hash)?;
+                        return Ok(Some((path, value)));
+                    }
+                }
+                Node::Internal((left, right)) => {
+                    let depth = subtree.depth;
+                    // right first, so the left child comes off the stack next
+                    if !right.is_placeholder() {
+                        self.stack.push(Subtree {
+                            hash: right,
+                            depth: depth + 1,
+                            lo: subtree.lo.with_bit(depth, true),
+                            hi: subtree.hi,
+                        });
+                    }
+                    if !left.is_placeholder() {
+                        self.stack.push(Subtree {
+                            hash: left,
+                            depth: depth + 1,
+                            lo: subtree.lo,
+                            hi: subtree.hi.with_bit(depth, false),
+                        });
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a, S: TreeStore<N>, H: TreeHasher<N>, const N: usize> Iterator for Iter<'a, S, H, N> {
+    type Item = Result<(HashValue<N>, Vec<u8>), N>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_leaf() {
+            Ok(leaf) => leaf.map(Ok),
+            Err(e) => {
+                self.stack.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{HashValue, SmtError, TreeStore, DEFAULT_VALUE};
+    use crate::{MemoryStore, SparseMerkleTree};
+
+    fn collect(
+        iter: impl Iterator<Item = crate::Result<(HashValue, Vec<u8>)>>,
+    ) -> Vec<(HashValue, Vec<u8>)> {
+        iter.map(|leaf| leaf.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_iter() {
+        let mut tree = SparseMerkleTree::new(None);
+        assert_eq!(tree.iter(tree.get_root()).count(), 0);
+
+        let mut expected = Vec::new();
+        for i in 0..100u8 {
+            assert!(tree.update(&[i], &[i, 1]).is_ok());
+            expected.push((HashValue::digest_of(&[i]), vec![i, 1]));
+        }
+        expected.sort();
+        let old_root = tree.get_root();
+        assert_eq!(collect(tree.iter(old_root)), expected);
+
+        // the old root still lists the old contents
+        assert!(tree.update(&[3], DEFAULT_VALUE).is_ok());
+        assert!(tree.update(&[200], b"new").is_ok());
+        assert_eq!(collect(tree.iter(old_root)), expected);
+
+        let leaves = collect(tree.iter(tree.get_root()));
+        assert_eq!(leaves.len(), 100);
+        assert!(leaves.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(leaves.contains(&(HashValue::digest_of(&[200]), b"new".to_vec())));
+        assert!(!leaves.iter().any(|(p, _)| *p == HashValue::digest_of(&[3])));
+    }
+
+    #[test]
+    fn test_range() {
+        let mut tree = SparseMerkleTree::new(None);
+        let mut paths = Vec::new();
+        for i in 0..64u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+            paths.push(HashValue::digest_of(&[i]));
+        }
+        paths.sort();
+        let root = tree.get_root();
+
+        let range: Vec<HashValue> = collect(tree.range(root, paths[10], paths[20]))
+            .into_iter()
+            .map(|(p, _)| p)
+            .collect();
+        assert_eq!(range, &paths[10..=20]);
+
+        // bounds between leaves
+        let start = paths[6].with_bit(255, false);
+        let end = paths[7].with_bit(255, true);
+        let range = collect(tree.range(root, start, end));
+        assert_eq!(range.len(), 2);
+        assert_eq!(range[0].0, paths[6]);
+
+        assert_eq!(tree.range(root, paths[20], paths[10]).count(), 0);
+        assert_eq!(tree.range(root, paths[63], paths[63]).count(), 1);
+    }
+
+    #[test]
+    fn test_range_skips_subtrees() {
+        let mut tree = SparseMerkleTree::new(None);
+        for i in 0..64u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let root = tree.get_root();
+        let mut paths: Vec<HashValue> = (0..64u8).map(|i| HashValue::digest_of(&[i])).collect();
+        paths.sort();
+
+        // drop a node in the right half; a range in the left half never touches it
+        let right = match tree.store().get_node(root).unwrap().unwrap() {
+            crate::Node::Internal((_, right)) => right,
+            _ => panic!("expected an internal root"),
+        };
+        let mut store: MemoryStore = tree.into_store();
+        assert!(store.delete_node(&right).is_ok());
+        let tree: SparseMerkleTree<_> = SparseMerkleTree::with_store(store, Some(root));
+
+        let left_end = HashValue::new([0x7f; 32]);
+        let left = collect(tree.range(root, paths[0], left_end));
+        assert!(!left.is_empty());
+        assert!(left.iter().all(|(p, _)| !p.has_bit_set(0)));
+
+        let mut all = tree.iter(root);
+        assert!(all.any(|leaf| matches!(leaf, Err(SmtError::MissingNode(_)))));
+        assert!(all.next().is_none());
+    }
+}
@@ -1,6 +1,50 @@
+#[macro_use]
+mod error;
+mod cache_store;
+#[cfg(feature = "celestia")]
+mod celestia;
+mod diff;
+mod encoding;
+mod file_store;
+mod gc;
+mod hasher;
+mod iter;
+mod proof;
+mod snapshot;
 mod store;
+mod subtree;
+mod sync;
+mod transaction;
+mod transition;
 mod tree;
 mod types;
 //mod utils;
 
+pub use self::cache_store::{CacheBudget, CacheStats, CacheStore};
+#[cfg(feature = "celestia")]
+pub use self::celestia::{CelestiaCompactProof, CelestiaProof, CelestiaTree};
+pub use self::diff::Change;
+pub use self::encoding::{WireFormat, ENCODING_VERSION};
+pub use self::error::{Result, SmtError};
+pub use self::file_store::FileStore;
+pub use self::gc::{GarbageCollector, GcStats};
+#[cfg(feature = "blake3")]
+pub use self::hasher::Blake3Hasher;
+#[cfg(feature = "keccak")]
+pub use self::hasher::Keccak256Hasher;
+#[cfg(feature = "sha256")]
+pub use self::hasher::Sha256Hasher;
+pub use self::hasher::{Blake2bHasher, Blake2sHasher, TreeHasher};
+pub use self::iter::Iter;
+pub use self::proof::{
+    verify_many, verify_proof, verify_proof_path, CompactSparseMerkleProof, SparseMerkleMultiProof,
+    SparseMerkleProof,
+};
+pub use self::snapshot::{SharedStore, Snapshot};
+pub use self::store::{MaybeSync, MemoryStore, TreeStore};
+pub use self::subtree::DeepSparseMerkleSubTree;
+pub use self::sync::{Chunk, ChunkImporter};
+pub use self::transaction::{Savepoint, Transaction};
+pub use self::transition::{verify_transition, TransitionWitness};
 pub use self::tree::SparseMerkleTree;
+pub use self::types::{EncodedNode, HashValue, Node, DEFAULT_VALUE};
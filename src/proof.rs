@@ -0,0 +1,643 @@
+//!
+//! Merkle proofs
+//!
+
+use std::marker::PhantomData;
+
+use crate::encoding::Reader;
+use crate::error::{Result, SmtError};
+use crate::hasher::{Blake2sHasher, TreeHasher};
+use crate::types::{EncodedNode, HashValue, Node, DEFAULT_VALUE};
+
+const HAS_LEAF_DATA: u8 = 1;
+const HAS_SIBLING_DATA: u8 = 1 << 1;
+
+/// Proof that a key is (or is not) in the tree under a given root.
+/// `H` is the hasher of the tree that made the proof.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparseMerkleProof<H = Blake2sHasher, const N: usize = 32> {
+    /// Sidenodes on the path to the key, from the bottom of the tree up to the root
+    pub sidenodes: Vec<HashValue<N>>,
+    /// The leaf found in place of the key, for non-membership proofs that don't end on a placeholder
+    pub non_membership_leaf_data: Option<EncodedNode>,
+    /// Data of the sibling node at the bottom of the path, set for updatable proofs
+    pub sibling_data: Option<EncodedNode>,
+    hasher: PhantomData<H>,
+}
+
+impl<H: TreeHasher<N>, const N: usize> SparseMerkleProof<H, N> {
+    pub fn new(
+        sidenodes: Vec<HashValue<N>>,
+        non_membership_leaf_data: Option<EncodedNode>,
+        sibling_data: Option<EncodedNode>,
+    ) -> Self {
+        Self {
+            sidenodes,
+            non_membership_leaf_data,
+            sibling_data,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Basic checks so a malicious proof can't make the verifier panic or spin
+    pub(crate) fn sanity_check(&self) -> bool {
+        if self.sidenodes.len() > HashValue::<N>::DEPTH {
+            return false;
+        }
+
+        if let Some(data) = &self.non_membership_leaf_data {
+            match Node::<N>::decode(data) {
+                Ok(n) if n.is_leaf() => {}
+                _ => return false,
+            }
+        }
+
+        // The sibling data must hash to the first sidenode
+        match (&self.sibling_data, self.sidenodes.first()) {
+            (Some(data), Some(sidenode)) => H::digest(data) == *sidenode,
+            _ => true,
+        }
+    }
+
+    /// Drop the placeholder sidenodes, marking their positions in a bitmask instead
+    pub fn compact(&self) -> Result<CompactSparseMerkleProof<H, N>, N> {
+        ensure!(self.sanity_check(), SmtError::InvalidProof("bad proof"));
+        Ok(self.compact_unchecked())
+    }
+
+    pub(crate) fn compact_unchecked(&self) -> CompactSparseMerkleProof<H, N> {
+        let mut bitmask = vec![0u8; self.sidenodes.len().div_ceil(8)];
+        let mut sidenodes = Vec::new();
+        for (i, sidenode) in self.sidenodes.iter().enumerate() {
+            if sidenode.is_placeholder() {
+                bitmask[i / 8] |= 1 << (7 - i % 8);
+            } else {
+                sidenodes.push(*sidenode);
+            }
+        }
+
+        CompactSparseMerkleProof {
+            sidenodes,
+            non_membership_leaf_data: self.non_membership_leaf_data.clone(),
+            bitmask,
+            num_sidenodes: self.sidenodes.len(),
+            sibling_data: self.sibling_data.clone(),
+            hasher: PhantomData,
+        }
+    }
+}
+
+/// A `SparseMerkleProof` without the placeholder sidenodes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactSparseMerkleProof<H = Blake2sHasher, const N: usize = 32> {
+    /// The non-placeholder sidenodes, in the same order as the full proof
+    pub sidenodes: Vec<HashValue<N>>,
+    pub non_membership_leaf_data: Option<EncodedNode>,
+    /// One bit per sidenode, most significant bit first. A set bit is a placeholder.
+    pub bitmask: Vec<u8>,
+    /// Number of sidenodes in the full proof
+    pub num_sidenodes: usize,
+    pub sibling_data: Option<EncodedNode>,
+    hasher: PhantomData<H>,
+}
+
+impl<H: TreeHasher<N>, const N: usize> CompactSparseMerkleProof<H, N> {
+    fn is_placeholder_at(&self, index: usize) -> bool {
+        (self.bitmask[index / 8] >> (7 - index % 8)) & 1 != 0
+    }
+
+    fn sanity_check(&self) -> Result<(), N> {
+        ensure!(
+            self.num_sidenodes <= HashValue::<N>::DEPTH,
+            SmtError::InvalidProof("too many sidenodes")
+        );
+        ensure!(
+            self.bitmask.len() == self.num_sidenodes.div_ceil(8),
+            SmtError::InvalidProof("bitmask has the wrong length")
+        );
+        // Bits past the last sidenode must be unset so the encoding stays canonical
+        if !self.num_sidenodes.is_multiple_of(8) {
+            let padding = self.bitmask[self.bitmask.len() - 1] & (0xff >> (self.num_sidenodes % 8));
+            ensure!(
+                padding == 0,
+                SmtError::InvalidProof("bitmask padding is set")
+            );
+        }
+        let placeholders: usize = self.bitmask.iter().map(|b| b.count_ones() as usize).sum();
+        ensure!(
+            self.sidenodes.len() == self.num_sidenodes - placeholders,
+            SmtError::InvalidProof("wrong number of sidenodes for the bitmask")
+        );
+        Ok(())
+    }
+
+    /// Rebuild the full proof, putting the placeholders back
+    pub fn decompact(&self) -> Result<SparseMerkleProof<H, N>, N> {
+        self.sanity_check()?;
+
+        let mut compacted = self.sidenodes.iter();
+        let mut sidenodes = Vec::with_capacity(self.num_sidenodes);
+        for i in 0..self.num_sidenodes {
+            if self.is_placeholder_at(i) {
+                sidenodes.push(HashValue::placeholder());
+            } else {
+                let sidenode = compacted
+                    .next()
+                    .ok_or(SmtError::InvalidProof("missing sidenode"))?;
+                sidenodes.push(*sidenode);
+            }
+        }
+
+        let proof = SparseMerkleProof::new(
+            sidenodes,
+            self.non_membership_leaf_data.clone(),
+            self.sibling_data.clone(),
+        );
+        ensure!(proof.sanity_check(), SmtError::InvalidProof("bad proof"));
+        Ok(proof)
+    }
+
+    /// Canonical encoding:
+    ///
+    /// `num_sidenodes (u16, big endian) | flags (u8) | bitmask | sidenodes | leaf data? | sibling data?`
+    ///
+    /// Flag bit 0 marks the non-membership leaf data, bit 1 the sibling data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0u8;
+        if self.non_membership_leaf_data.is_some() {
+            flags |= HAS_LEAF_DATA;
+        }
+        if self.sibling_data.is_some() {
+            flags |= HAS_SIBLING_DATA;
+        }
+
+        let mut out = Vec::new();
+        out.extend(&(self.num_sidenodes as u16).to_be_bytes());
+        out.push(flags);
+        out.extend(&self.bitmask);
+        for sidenode in &self.sidenodes {
+            out.extend(sidenode.as_ref());
+        }
+        if let Some(data) = &self.non_membership_leaf_data {
+            out.extend(data);
+        }
+        if let Some(data) = &self.sibling_data {
+            out.extend(data);
+        }
+        out
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<Self, N> {
+        let mut reader = Reader::<N>(raw);
+
+        let mut num = [0u8; 2];
+        num.copy_from_slice(reader.take(2)?);
+        let num_sidenodes = u16::from_be_bytes(num) as usize;
+        ensure!(
+            num_sidenodes <= HashValue::<N>::DEPTH,
+            SmtError::InvalidProof("too many sidenodes")
+        );
+
+        let flags = reader.take(1)?[0];
+        ensure!(
+            flags & !(HAS_LEAF_DATA | HAS_SIBLING_DATA) == 0,
+            SmtError::InvalidProof("unknown flags")
+        );
+
+        let bitmask = reader.take(num_sidenodes.div_ceil(8))?.to_vec();
+        let placeholders: usize = bitmask.iter().map(|b| b.count_ones() as usize).sum();
+        ensure!(
+            placeholders <= num_sidenodes,
+            SmtError::InvalidProof("bitmask has too many bits set")
+        );
+
+        let mut sidenodes = Vec::with_capacity(num_sidenodes - placeholders);
+        for _ in 0..num_sidenodes - placeholders {
+            sidenodes.push(reader.take_hash()?);
+        }
+
+        let non_membership_leaf_data = match flags & HAS_LEAF_DATA {
+            0 => None,
+            _ => Some(reader.take_node()?),
+        };
+        let sibling_data = match flags & HAS_SIBLING_DATA {
+            0 => None,
+            _ => Some(reader.take_node()?),
+        };
+        ensure!(
+            reader.0.is_empty(),
+            SmtError::InvalidProof("trailing bytes")
+        );
+
+        let proof = Self {
+            sidenodes,
+            non_membership_leaf_data,
+            bitmask,
+            num_sidenodes,
+            sibling_data,
+            hasher: PhantomData,
+        };
+        proof.sanity_check()?;
+        Ok(proof)
+    }
+}
+
+/// Verify a proof for `key` against `root` without needing a store.
+/// Passing `DEFAULT_VALUE` as `value` checks a non-membership proof.
+pub fn verify_proof<H: TreeHasher<N>, const N: usize>(
+    proof: &SparseMerkleProof<H, N>,
+    root: HashValue<N>,
+    key: &[u8],
+    value: &[u8],
+) -> bool {
+    verify_proof_path(proof, root, H::digest(key), value)
+}
+
+/// Like `verify_proof`, for a proof from `SparseMerkleTree::prove_path`
+pub fn verify_proof_path<H: TreeHasher<N>, const N: usize>(
+    proof: &SparseMerkleProof<H, N>,
+    root: HashValue<N>,
+    path: HashValue<N>,
+    value: &[u8],
+) -> bool {
+    if !proof.sanity_check() {
+        return false;
+    }
+
+    let leaf = if value == DEFAULT_VALUE {
+        match &proof.non_membership_leaf_data {
+            // The key ends on an empty subtree
+            None => None,
+            Some(data) => match Node::<N>::decode(data) {
+                // A different leaf sits where the key would be
+                Ok(Node::Leaf((actual_path, value_hash))) if actual_path != path => {
+                    Some(Node::new_leaf(actual_path, value_hash))
+                }
+                _ => return false,
+            },
+        }
+    } else {
+        Some(Node::new_leaf(path, H::digest(value)))
+    };
+
+    let mut current_hash = match leaf {
+        Some(n) => n.hash::<H>(),
+        None => HashValue::placeholder(),
+    };
+
+    let len = proof.sidenodes.len();
+    for (i, sidenode) in proof.sidenodes.iter().enumerate() {
+        let node = match path.has_bit_set(len - 1 - i) {
+            // go right
+            true => Node::new_internal(*sidenode, current_hash),
+            _ => Node::new_internal(current_hash, *sidenode),
+        };
+        current_hash = node.hash::<H>();
+    }
+
+    current_hash == root
+}
+
+/// Proof for many keys at once. Sidenodes shared between the keys' paths
+/// appear once, and sidenodes on another proven path are left out.
+///
+/// The per-key fields follow the order of the keys' paths, with duplicates
+/// removed. `depths` and `leaf_data` must be the same length: encoding a proof
+/// where they aren't panics.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SparseMerkleMultiProof<H = Blake2sHasher, const N: usize = 32> {
+    /// Depth at which the walk for each path ended on a leaf or placeholder
+    pub depths: Vec<usize>,
+    /// The leaf found in place of each key, for non-members that don't end on a placeholder
+    pub leaf_data: Vec<Option<EncodedNode>>,
+    /// Sidenodes off the union of the paths, in depth-first, left to right order
+    pub sidenodes: Vec<HashValue<N>>,
+    hasher: PhantomData<H>,
+}
+
+impl<H: TreeHasher<N>, const N: usize> SparseMerkleMultiProof<H, N> {
+    pub fn new(
+        depths: Vec<usize>,
+        leaf_data: Vec<Option<EncodedNode>>,
+        sidenodes: Vec<HashValue<N>>,
+    ) -> Self {
+        Self {
+            depths,
+            leaf_data,
+            sidenodes,
+            hasher: PhantomData,
+        }
+    }
+}
+
+/// Where a proven path ends: its depth and the hash of the leaf or placeholder there
+struct PathEnd<const N: usize> {
+    path: HashValue<N>,
+    depth: usize,
+    hash: HashValue<N>,
+}
+
+/// Verify a multiproof for `entries` against `root`. Entries with
+/// `DEFAULT_VALUE` are non-membership claims.
+pub fn verify_many<H, K, V, const N: usize>(
+    proof: &SparseMerkleMultiProof<H, N>,
+    root: HashValue<N>,
+    entries: &[(K, V)],
+) -> bool
+where
+    H: TreeHasher<N>,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut claims: Vec<(HashValue<N>, &[u8])> = entries
+        .iter()
+        .map(|(k, v)| (H::digest(k.as_ref()), v.as_ref()))
+        .collect();
+    claims.sort_by_key(|(path, _)| *path);
+    // The same key twice has to make the same claim
+    for pair in claims.windows(2) {
+        if pair[0].0 == pair[1].0 && pair[0].1 != pair[1].1 {
+            return false;
+        }
+    }
+    claims.dedup_by_key(|(path, _)| *path);
+
+    if claims.len() != proof.depths.len() || claims.len() != proof.leaf_data.len() {
+        return false;
+    }
+
+    let mut ends = Vec::with_capacity(claims.len());
+    for ((path, value), (depth, leaf_data)) in
+        claims.iter().zip(proof.depths.iter().zip(&proof.leaf_data))
+    {
+        if *depth > HashValue::<N>::DEPTH {
+            return false;
+        }
+        let hash = match (*value == DEFAULT_VALUE, leaf_data) {
+            (false, None) => Node::new_leaf(*path, H::digest(value)).hash::<H>(),
+            (false, Some(_)) => return false,
+            (true, None) => HashValue::placeholder(),
+            (true, Some(data)) => match Node::<N>::decode(data) {
+                // A different leaf sits where the key would be
+                Ok(leaf @ Node::Leaf((actual_path, _)))
+                    if actual_path != *path
+                        && actual_path.common_prefix_bits_len(*path) >= *depth =>
+                {
+                    leaf.hash::<H>()
+                }
+                _ => return false,
+            },
+        };
+        ends.push(PathEnd {
+            path: *path,
+            depth: *depth,
+            hash,
+        });
+    }
+
+    let mut sidenodes = proof.sidenodes.iter();
+    match multi_root::<H, N>(&ends, 0, &mut sidenodes) {
+        Some(computed) => computed == root && sidenodes.next().is_none(),
+        None => false,
+    }
+}
+
+/// Hash of the subtree at `depth` holding the sorted `ends`, or `None` if they're inconsistent
+fn multi_root<'a, H: TreeHasher<N>, const N: usize>(
+    ends: &[PathEnd<N>],
+    depth: usize,
+    sidenodes: &mut impl Iterator<Item = &'a HashValue<N>>,
+) -> Option<HashValue<N>> {
+    let first = ends.first()?;
+    if first.depth == depth {
+        // Every path here must end on the same node
+        return match ends
+            .iter()
+            .all(|e| e.depth == depth && e.hash == first.hash)
+        {
+            true => Some(first.hash),
+            false => None,
+        };
+    }
+    if depth == HashValue::<N>::DEPTH || ends.iter().any(|e| e.depth <= depth) {
+        return None;
+    }
+
+    let split = ends.partition_point(|e| !e.path.has_bit_set(depth));
+    let (left, right) = ends.split_at(split);
+    let left = match left.is_empty() {
+        true => *sidenodes.next()?,
+        false => multi_root::<H, N>(left, depth + 1, sidenodes)?,
+    };
+    let right = match right.is_empty() {
+        true => *sidenodes.next()?,
+        false => multi_root::<H, N>(right, depth + 1, sidenodes)?,
+    };
+    Some(Node::new_internal(left, right).hash::<H>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SparseMerkleTree;
+
+    #[test]
+    fn test_proofs() {
+        let mut tree = SparseMerkleTree::new(None);
+
+        // empty tree
+        let proof = tree.prove(b"a").unwrap();
+        assert!(proof.sidenodes.is_empty());
+        assert!(verify_proof(&proof, tree.get_root(), b"a", DEFAULT_VALUE));
+        assert!(!verify_proof(&proof, tree.get_root(), b"a", b"a1"));
+
+        assert!(tree.update(b"a", b"a1").is_ok());
+        let proof = tree.prove(b"a").unwrap();
+        assert!(verify_proof(&proof, tree.get_root(), b"a", b"a1"));
+        assert!(!verify_proof(&proof, tree.get_root(), b"a", b"a2"));
+        assert!(!verify_proof(&proof, tree.get_root(), b"a", DEFAULT_VALUE));
+
+        // a single leaf root proves the absence of any other key
+        let proof = tree.prove(b"b").unwrap();
+        assert!(proof.non_membership_leaf_data.is_some());
+        assert!(verify_proof(&proof, tree.get_root(), b"b", DEFAULT_VALUE));
+        assert!(!verify_proof(&proof, tree.get_root(), b"b", b"b1"));
+
+        for (k, v) in &[(b"b", b"b1"), (b"c", b"c1"), (b"d", b"d1"), (b"e", b"e1")] {
+            assert!(tree.update(*k, *v).is_ok());
+        }
+        let root = tree.get_root();
+
+        for (k, v) in &[
+            (b"a", b"a1"),
+            (b"b", b"b1"),
+            (b"c", b"c1"),
+            (b"d", b"d1"),
+            (b"e", b"e1"),
+        ] {
+            let proof = tree.prove(*k).unwrap();
+            assert!(verify_proof(&proof, root, *k, *v));
+            assert!(!verify_proof(&proof, root, *k, b"bad"));
+            assert!(!verify_proof(&proof, root, *k, DEFAULT_VALUE));
+            assert!(!verify_proof(&proof, HashValue::placeholder(), *k, *v));
+        }
+
+        for k in &[b"x", b"y", b"z"] {
+            let proof = tree.prove(*k).unwrap();
+            assert!(verify_proof(&proof, root, *k, DEFAULT_VALUE));
+            assert!(!verify_proof(&proof, root, *k, b"x1"));
+        }
+
+        let proof = tree.prove_updatable(b"c").unwrap();
+        assert!(proof.sibling_data.is_some());
+        assert!(verify_proof(&proof, root, b"c", b"c1"));
+    }
+
+    #[test]
+    fn test_bad_proofs() {
+        let mut tree = SparseMerkleTree::new(None);
+        for (k, v) in &[(b"a", b"a1"), (b"b", b"b1"), (b"c", b"c1")] {
+            assert!(tree.update(*k, *v).is_ok());
+        }
+        let root = tree.get_root();
+
+        let mut proof = tree.prove(b"a").unwrap();
+        proof.sidenodes = vec![HashValue::placeholder(); HashValue::<32>::DEPTH + 1];
+        assert!(!verify_proof(&proof, root, b"a", b"a1"));
+
+        // the leaf of the key itself isn't a non-membership proof
+        let mut proof = tree.prove(b"a").unwrap();
+        proof.non_membership_leaf_data = Some(
+            Node::new_leaf(HashValue::digest_of(b"a"), HashValue::digest_of(b"a1")).to_bytes(),
+        );
+        assert!(!verify_proof(&proof, root, b"a", DEFAULT_VALUE));
+
+        let mut proof = tree.prove_updatable(b"b").unwrap();
+        proof.sibling_data = Some(vec![1u8; 65]);
+        assert!(!verify_proof(&proof, root, b"b", b"b1"));
+    }
+
+    #[test]
+    fn test_compact_proofs() {
+        let mut tree = SparseMerkleTree::new(None);
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i, i]).is_ok());
+        }
+        let root = tree.get_root();
+
+        for i in 0..60u8 {
+            let value: &[u8] = if i < 50 { &[i, i] } else { DEFAULT_VALUE };
+            let proof = tree.prove_updatable(&[i]).unwrap();
+            let compact = proof.compact().unwrap();
+            assert!(compact.sidenodes.len() <= proof.sidenodes.len());
+
+            let decoded =
+                CompactSparseMerkleProof::<Blake2sHasher>::from_bytes(&compact.to_bytes()).unwrap();
+            assert_eq!(decoded, compact);
+
+            let decompacted = decoded.decompact().unwrap();
+            assert_eq!(decompacted, proof);
+            assert!(verify_proof(&decompacted, root, &[i], value));
+        }
+    }
+
+    #[test]
+    fn test_bad_compact_proofs() {
+        let mut tree = SparseMerkleTree::new(None);
+        for i in 0..10u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let compact = tree.prove(&[3]).unwrap().compact().unwrap();
+
+        let mut bad = compact.clone();
+        bad.bitmask.push(0);
+        assert!(bad.decompact().is_err());
+
+        let mut bad = compact.clone();
+        bad.sidenodes.push(HashValue::placeholder());
+        assert!(bad.decompact().is_err());
+
+        let mut bad = compact.clone();
+        bad.num_sidenodes = HashValue::<32>::DEPTH + 1;
+        assert!(bad.decompact().is_err());
+
+        let mut bad = compact.clone();
+        bad.bitmask = vec![0xff; bad.bitmask.len()];
+        assert!(bad.decompact().is_err());
+
+        let raw = compact.to_bytes();
+        for len in 0..raw.len() {
+            assert!(CompactSparseMerkleProof::<Blake2sHasher>::from_bytes(&raw[..len]).is_err());
+        }
+        let mut trailing = raw.clone();
+        trailing.push(0);
+        assert!(CompactSparseMerkleProof::<Blake2sHasher>::from_bytes(&trailing).is_err());
+        assert!(CompactSparseMerkleProof::<Blake2sHasher>::from_bytes(&[0xff, 0xff, 0]).is_err());
+        assert!(
+            CompactSparseMerkleProof::<Blake2sHasher>::from_bytes(&[0, 1, 0xf0, 0x80]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_multiproofs() {
+        let mut tree = SparseMerkleTree::new(None);
+
+        // empty tree
+        let proof = tree.prove_many(&[b"a", b"b"]).unwrap();
+        let absent: Vec<(&[u8], &[u8])> = vec![(b"a", DEFAULT_VALUE), (b"b", DEFAULT_VALUE)];
+        assert!(verify_many(&proof, tree.get_root(), &absent));
+
+        for i in 0..200u8 {
+            assert!(tree.update(&[i], &[i, i]).is_ok());
+        }
+        let root = tree.get_root();
+
+        // members, non-members, and the same key twice
+        let mut entries: Vec<(Vec<u8>, Vec<u8>)> =
+            (0..50u8).map(|i| (vec![i], vec![i, i])).collect();
+        entries.push((vec![250], DEFAULT_VALUE.to_vec()));
+        entries.push((vec![251], DEFAULT_VALUE.to_vec()));
+        entries.push((vec![3], vec![3, 3]));
+        let keys: Vec<Vec<u8>> = entries.iter().map(|(k, _)| k.clone()).collect();
+        let proof = tree.prove_many(&keys).unwrap();
+        assert_eq!(proof.depths.len(), 52);
+        assert!(verify_many(&proof, root, &entries));
+
+        // far smaller than the separate proofs
+        let separate: usize = keys
+            .iter()
+            .map(|k| tree.prove(k).unwrap().sidenodes.len())
+            .sum();
+        assert!(proof.sidenodes.len() * 2 < separate);
+
+        // wrong claims
+        let mut wrong = entries.clone();
+        wrong[0].1 = b"x".to_vec();
+        assert!(!verify_many(&proof, root, &wrong));
+        let mut wrong = entries.clone();
+        wrong[50].1 = b"x".to_vec();
+        assert!(!verify_many(&proof, root, &wrong));
+        let mut wrong = entries.clone();
+        wrong[1].1 = DEFAULT_VALUE.to_vec();
+        assert!(!verify_many(&proof, root, &wrong));
+        let mut wrong = entries.clone();
+        wrong[52].1 = b"x".to_vec();
+        assert!(!verify_many(&proof, root, &wrong));
+        assert!(!verify_many(&proof, root, &entries[1..]));
+        assert!(!verify_many(&proof, HashValue::digest_of(b"x"), &entries));
+
+        // tampered proofs
+        let mut bad = proof.clone();
+        bad.sidenodes.pop();
+        assert!(!verify_many(&bad, root, &entries));
+        let mut bad = proof.clone();
+        bad.sidenodes.push(HashValue::placeholder());
+        assert!(!verify_many(&bad, root, &entries));
+        let mut bad = proof.clone();
+        bad.depths[0] += 1;
+        assert!(!verify_many(&bad, root, &entries));
+        let mut bad = proof;
+        bad.depths[0] = HashValue::<32>::DEPTH + 1;
+        assert!(!verify_many(&bad, root, &entries));
+    }
+}
@@ -0,0 +1,279 @@
+//!
+//! Shared stores and snapshots
+//!
+//! A `SharedStore` puts a store behind a lock so one writer and any number of
+//! read-only `Snapshot`s can use it from different threads. The shared store
+//! pins the roots of live snapshots, so garbage collection keeps them.
+//!
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::error::{Result, SmtError};
+use crate::hasher::{Blake2sHasher, TreeHasher};
+use crate::iter::Iter;
+use crate::proof::{SparseMerkleMultiProof, SparseMerkleProof};
+use crate::store::TreeStore;
+use crate::tree::SparseMerkleTree;
+use crate::types::{HashValue, Node};
+
+/// A `TreeStore` handle that can be cloned and shared between threads.
+/// Every clone reads and writes the same store.
+pub struct SharedStore<S: TreeStore> {
+    inner: Arc<RwLock<S>>,
+    /// Roots of live snapshots, with how many snapshots hold each
+    pins: Arc<Mutex<HashMap<HashValue, usize>>>,
+}
+
+impl<S: TreeStore> Clone for SharedStore<S> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            pins: Arc::clone(&self.pins),
+        }
+    }
+}
+
+impl<S: TreeStore> SharedStore<S> {
+    pub fn new(store: S) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(store)),
+            pins: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn pin(&self, root: HashValue) {
+        *self.pins().entry(root).or_insert(0) += 1;
+    }
+
+    fn unpin(&self, root: HashValue) {
+        let mut pins = self.pins();
+        if let Some(count) = pins.get_mut(&root) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&root);
+            }
+        }
+    }
+
+    /// The counts stay right even if a holder panicked, so poisoning is ignored
+    fn pins(&self) -> MutexGuard<'_, HashMap<HashValue, usize>> {
+        self.pins.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, S>> {
+        self.inner
+            .read()
+            .map_err(|_| SmtError::Storage("shared store lock poisoned".into()))
+    }
+
+    fn write(&self) -> Result<RwLockWriteGuard<'_, S>> {
+        self.inner
+            .write()
+            .map_err(|_| SmtError::Storage("shared store lock poisoned".into()))
+    }
+}
+
+impl<S: TreeStore> TreeStore for SharedStore<S> {
+    fn get_root(&self) -> Result<Option<HashValue>> {
+        self.read()?.get_root()
+    }
+
+    fn set_root(&mut self, root: HashValue) -> Result<()> {
+        self.write()?.set_root(root)
+    }
+
+    fn get_node(&self, key: HashValue) -> Result<Option<Node>> {
+        self.read()?.get_node(key)
+    }
+
+    fn set_node(&mut self, key: HashValue, node: Node) -> Result<()> {
+        self.write()?.set_node(key, node)
+    }
+
+    fn delete_node(&mut self, key: &HashValue) -> Result<()> {
+        self.write()?.delete_node(key)
+    }
+
+    fn get_value(&self, key: HashValue) -> Result<Option<Vec<u8>>> {
+        self.read()?.get_value(key)
+    }
+
+    fn set_value(&mut self, key: HashValue, value: &[u8]) -> Result<()> {
+        self.write()?.set_value(key, value)
+    }
+
+    fn delete_value(&mut self, key: &HashValue) -> Result<()> {
+        self.write()?.delete_value(key)
+    }
+
+    fn node_keys(&self) -> Result<Vec<HashValue>> {
+        self.read()?.node_keys()
+    }
+
+    fn value_keys(&self) -> Result<Vec<HashValue>> {
+        self.read()?.value_keys()
+    }
+
+    fn pinned_roots(&self) -> Result<Vec<HashValue>> {
+        let mut roots = self.read()?.pinned_roots()?;
+        roots.extend(self.pins().keys());
+        Ok(roots)
+    }
+}
+
+/// A read-only view of a tree at a fixed root, from `SparseMerkleTree::snapshot`.
+///
+/// Updates never delete nodes or values, and the snapshot's root stays
+/// pinned in the shared store until it is dropped, so garbage collection
+/// through the shared store keeps it too. A collection run on the inner store
+/// directly doesn't see the pins and can break a live snapshot.
+pub struct Snapshot<S: TreeStore, H: TreeHasher = Blake2sHasher> {
+    tree: SparseMerkleTree<SharedStore<S>, H>,
+}
+
+impl<S: TreeStore, H: TreeHasher> Snapshot<S, H> {
+    fn new(store: &SharedStore<S>, root: HashValue) -> Self {
+        store.pin(root);
+        Self {
+            tree: SparseMerkleTree::with_store(store.clone(), Some(root)),
+        }
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> Clone for Snapshot<S, H> {
+    fn clone(&self) -> Self {
+        Self::new(self.tree.store(), self.get_root())
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> Drop for Snapshot<S, H> {
+    fn drop(&mut self) {
+        self.tree.store().unpin(self.get_root());
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> SparseMerkleTree<SharedStore<S>, H> {
+    /// A handle on the current root that other threads can read from
+    pub fn snapshot(&self) -> Snapshot<S, H> {
+        Snapshot::new(self.store(), self.get_root())
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> Snapshot<S, H> {
+    pub fn get_root(&self) -> HashValue {
+        self.tree.get_root()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.tree.get(key)
+    }
+
+    pub fn prove(&self, key: &[u8]) -> Result<SparseMerkleProof<H>> {
+        self.tree.prove(key)
+    }
+
+    pub fn prove_updatable(&self, key: &[u8]) -> Result<SparseMerkleProof<H>> {
+        self.tree.prove_updatable(key)
+    }
+
+    pub fn prove_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<SparseMerkleMultiProof<H>> {
+        self.tree.prove_many(keys)
+    }
+
+    pub fn iter(&self) -> Iter<'_, SharedStore<S>, H> {
+        self.tree.iter(self.get_root())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{verify_proof, CacheStore, FileStore, MemoryStore};
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<SparseMerkleTree>();
+        assert_send_sync::<SparseMerkleTree<FileStore>>();
+        assert_send_sync::<CacheStore<MemoryStore>>();
+        assert_send_sync::<SharedStore<FileStore>>();
+        assert_send_sync::<Snapshot<MemoryStore>>();
+    }
+
+    #[test]
+    fn test_concurrent_snapshot() {
+        let mut tree: SparseMerkleTree<_> =
+            SparseMerkleTree::with_store(SharedStore::new(MemoryStore::new()), None);
+        for i in 0..100u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let snapshot = tree.snapshot();
+        let root = snapshot.get_root();
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let snapshot = snapshot.clone();
+                thread::spawn(move || {
+                    for i in 0..100u8 {
+                        assert_eq!(snapshot.get(&[i]).unwrap(), vec![i]);
+                        let proof = snapshot.prove(&[i]).unwrap();
+                        assert!(verify_proof(&proof, snapshot.get_root(), &[i], &[i]));
+                    }
+                })
+            })
+            .collect();
+
+        // the writer goes on meanwhile, overwriting and deleting keys too
+        for i in 100..200u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        assert!(tree.update(&[1], b"new").is_ok());
+        assert!(tree.update(&[2], b"").is_ok());
+        for reader in readers {
+            assert!(reader.join().is_ok());
+        }
+
+        assert_eq!(snapshot.get_root(), root);
+        assert_eq!(snapshot.iter().count(), 100);
+        assert_eq!(snapshot.get(&[1]).unwrap(), vec![1]);
+        assert_eq!(snapshot.get(&[2]).unwrap(), vec![2]);
+        assert!(matches!(snapshot.get(&[150]), Err(SmtError::KeyNotFound)));
+        assert_eq!(tree.get(&[150]).unwrap(), vec![150]);
+        assert_eq!(tree.snapshot().iter().count(), 199);
+    }
+
+    #[test]
+    fn test_snapshot_survives_gc() {
+        let mut tree: SparseMerkleTree<_> =
+            SparseMerkleTree::with_store(SharedStore::new(MemoryStore::new()), None);
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        let snapshot = tree.snapshot();
+        let copy = snapshot.clone();
+        let root = snapshot.get_root();
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], b"new").is_ok());
+        }
+
+        assert!(tree.collect_garbage(&[]).unwrap().nodes_freed > 0);
+        drop(snapshot);
+        assert!(tree.collect_garbage(&[]).is_ok());
+        assert_eq!(copy.iter().count(), 50);
+        for i in 0..50u8 {
+            assert_eq!(copy.get(&[i]).unwrap(), vec![i]);
+        }
+
+        // once the last snapshot is gone so is its root
+        drop(copy);
+        assert_eq!(tree.store().pinned_roots().unwrap(), vec![]);
+        assert!(tree.collect_garbage(&[]).unwrap().nodes_freed > 0);
+        assert!(matches!(
+            tree.get_for_root(&[0], root),
+            Err(SmtError::MissingNode(_))
+        ));
+    }
+}
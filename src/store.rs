@@ -2,48 +2,282 @@
 //! Store stuff
 //!
 
-use crate::types::{EncodedNode, HashValue};
-use anyhow::{anyhow, Result};
+use crate::error::Result;
+use crate::hasher::TreeHasher;
+use crate::types::{HashValue, Node};
 use std::collections::HashMap;
+use std::io;
 
-pub struct MemoryStore {
-    nodes: HashMap<HashValue, Vec<u8>>,
-    values: HashMap<HashValue, Vec<u8>>,
+/// Backend for the nodes and values of a `SparseMerkleTree`.
+///
+/// Nodes are keyed by their hash. Values are keyed by the hash of their leaf,
+/// so each version of a key keeps its own entry and old roots can still read
+/// theirs. Nothing is reference counted: updates never delete, and
+/// unreachable nodes and values are freed by garbage collection.
+/// Getters return `None` for missing keys, and deleting a missing key is not an error.
+/// `N` is the width of the tree's hashes.
+pub trait TreeStore<const N: usize = 32> {
+    /// The root last passed to `set_root`. Stores that don't persist a root return `None`.
+    fn get_root(&self) -> Result<Option<HashValue<N>>, N> {
+        Ok(None)
+    }
+
+    /// Mark `root` as committed. Persistent stores should make everything
+    /// written so far durable before returning.
+    fn set_root(&mut self, _root: HashValue<N>) -> Result<(), N> {
+        Ok(())
+    }
+
+    fn get_node(&self, key: HashValue<N>) -> Result<Option<Node<N>>, N>;
+
+    fn set_node(&mut self, key: HashValue<N>, node: Node<N>) -> Result<(), N>;
+
+    fn delete_node(&mut self, key: &HashValue<N>) -> Result<(), N>;
+
+    fn get_value(&self, key: HashValue<N>) -> Result<Option<Vec<u8>>, N>;
+
+    fn set_value(&mut self, key: HashValue<N>, value: &[u8]) -> Result<(), N>;
+
+    fn delete_value(&mut self, key: &HashValue<N>) -> Result<(), N>;
+
+    /// Every node key, for garbage collection. Stores that can't list their
+    /// keys keep the default, which fails.
+    fn node_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "store can't list its nodes").into())
+    }
+
+    /// Every value key, for garbage collection
+    fn value_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "store can't list its values").into())
+    }
+
+    /// Roots in use elsewhere, such as by live snapshots, which garbage
+    /// collection must keep
+    fn pinned_roots(&self) -> Result<Vec<HashValue<N>>, N> {
+        Ok(Vec::new())
+    }
 }
 
-impl MemoryStore {
+/// The store key of the value of the leaf `(path, value_hash)`, which is the
+/// leaf's own hash
+pub(crate) fn value_key<H: TreeHasher<N>, const N: usize>(
+    path: HashValue<N>,
+    value_hash: HashValue<N>,
+) -> HashValue<N> {
+    Node::new_leaf(path, value_hash).hash::<H>()
+}
+
+/// `Sync` when the `parallel` feature is on, so batch updates can read the
+/// store from several threads. Every type implements it otherwise.
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Sync {}
+
+#[cfg(feature = "parallel")]
+impl<T: Sync> MaybeSync for T {}
+
+/// `Sync` when the `parallel` feature is on, so batch updates can read the
+/// store from several threads. Every type implements it otherwise.
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+
+#[cfg(not(feature = "parallel"))]
+impl<T> MaybeSync for T {}
+
+/// The default in memory `TreeStore`
+pub struct MemoryStore<const N: usize = 32> {
+    nodes: HashMap<HashValue<N>, Vec<u8>>,
+    values: HashMap<HashValue<N>, Vec<u8>>,
+    root: Option<HashValue<N>>,
+}
+
+impl<const N: usize> Default for MemoryStore<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MemoryStore<N> {
     pub fn new() -> Self {
         Self {
             nodes: HashMap::new(),
             values: HashMap::new(),
+            root: None,
+        }
+    }
+}
+
+impl<const N: usize> TreeStore<N> for MemoryStore<N> {
+    fn get_root(&self) -> Result<Option<HashValue<N>>, N> {
+        Ok(self.root)
+    }
+
+    fn set_root(&mut self, root: HashValue<N>) -> Result<(), N> {
+        self.root = Some(root);
+        Ok(())
+    }
+
+    fn get_node(&self, key: HashValue<N>) -> Result<Option<Node<N>>, N> {
+        match self.nodes.get(&key) {
+            Some(raw) => Node::decode(raw).map(Some),
+            None => Ok(None),
         }
     }
 
-    pub fn get_value(&self, key: HashValue) -> Result<&Vec<u8>> {
-        self.values.get(&key).ok_or(anyhow!("Invalid Key"))
+    fn set_node(&mut self, key: HashValue<N>, node: Node<N>) -> Result<(), N> {
+        self.nodes.insert(key, node.to_bytes());
+        Ok(())
     }
 
-    pub fn set_value(&mut self, key: HashValue, value: &[u8]) -> anyhow::Result<()> {
+    fn delete_node(&mut self, key: &HashValue<N>) -> Result<(), N> {
+        self.nodes.remove(key);
+        Ok(())
+    }
+
+    fn get_value(&self, key: HashValue<N>) -> Result<Option<Vec<u8>>, N> {
+        Ok(self.values.get(&key).cloned())
+    }
+
+    fn set_value(&mut self, key: HashValue<N>, value: &[u8]) -> Result<(), N> {
         self.values.insert(key, value.to_vec());
         Ok(())
     }
 
-    pub fn delete_value(&mut self, key: &HashValue) -> Option<Vec<u8>> {
-        self.values.remove(key)
+    fn delete_value(&mut self, key: &HashValue<N>) -> Result<(), N> {
+        self.values.remove(key);
+        Ok(())
+    }
+
+    fn node_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        Ok(self.nodes.keys().copied().collect())
+    }
+
+    fn value_keys(&self) -> Result<Vec<HashValue<N>>, N> {
+        Ok(self.values.keys().copied().collect())
+    }
+}
+
+/// A `MemoryStore` wrapper for tests that counts node reads and writes, and
+/// can fail writes or refuse to list its keys
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct TestStore {
+    pub(crate) inner: MemoryStore,
+    pub(crate) node_reads: std::sync::atomic::AtomicUsize,
+    pub(crate) node_writes: usize,
+    pub(crate) read_only: bool,
+    /// Keep the default `node_keys` and `value_keys`, which fail
+    pub(crate) unlisted: bool,
+}
+
+#[cfg(test)]
+impl TestStore {
+    pub(crate) fn new(inner: MemoryStore) -> Self {
+        Self {
+            inner,
+            ..Self::default()
+        }
+    }
+
+    fn check_write(&self) -> Result<()> {
+        if self.read_only {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, "read only").into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl TreeStore for TestStore {
+    fn get_node(&self, key: HashValue) -> Result<Option<Node>> {
+        self.node_reads
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self.inner.get_node(key)
+    }
+
+    fn set_node(&mut self, key: HashValue, node: Node) -> Result<()> {
+        self.check_write()?;
+        self.node_writes += 1;
+        self.inner.set_node(key, node)
+    }
+
+    fn delete_node(&mut self, key: &HashValue) -> Result<()> {
+        self.check_write()?;
+        self.inner.delete_node(key)
+    }
+
+    fn get_value(&self, key: HashValue) -> Result<Option<Vec<u8>>> {
+        self.inner.get_value(key)
+    }
+
+    fn set_value(&mut self, key: HashValue, value: &[u8]) -> Result<()> {
+        self.check_write()?;
+        self.inner.set_value(key, value)
+    }
+
+    fn delete_value(&mut self, key: &HashValue) -> Result<()> {
+        self.check_write()?;
+        self.inner.delete_value(key)
     }
 
-    // TODO: Actually return the node??
-    pub fn get_node(&self, key: HashValue) -> Result<&Vec<u8>> {
-        self.nodes.get(&key).ok_or(anyhow!("Invalid Key"))
+    fn node_keys(&self) -> Result<Vec<HashValue>> {
+        if self.unlisted {
+            return Err(
+                io::Error::new(io::ErrorKind::Unsupported, "store can't list its nodes").into(),
+            );
+        }
+        self.inner.node_keys()
     }
 
-    // TODO: Take the node as a parameter
-    pub fn set_node(&mut self, key: HashValue, value: EncodedNode) -> anyhow::Result<HashValue> {
-        self.nodes.insert(key, value.to_vec());
-        Ok(key)
+    fn value_keys(&self) -> Result<Vec<HashValue>> {
+        if self.unlisted {
+            return Err(
+                io::Error::new(io::ErrorKind::Unsupported, "store can't list its values").into(),
+            );
+        }
+        self.inner.value_keys()
+    }
+}
+
+/// A tree with the keys `[0]` to `[count - 1]`, each set to itself
+#[cfg(test)]
+pub(crate) fn build_tree(count: u8) -> crate::SparseMerkleTree {
+    let mut tree = crate::SparseMerkleTree::new(None);
+    for i in 0..count {
+        assert!(tree.update(&[i], &[i]).is_ok());
     }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SmtError, SparseMerkleTree};
+
+    #[test]
+    fn test_custom_store() {
+        let mut tree = SparseMerkleTree::new(None);
+        assert!(tree.update(b"a", b"a1").is_ok());
+        assert!(tree.update(b"b", b"b1").is_ok());
+        let root = tree.get_root();
 
-    pub fn delete_node(&mut self, key: &HashValue) -> Option<Vec<u8>> {
-        self.nodes.remove(key)
+        let mut tree: SparseMerkleTree<_> = SparseMerkleTree::with_store(
+            TestStore {
+                read_only: true,
+                ..TestStore::new(tree.into_store())
+            },
+            Some(root),
+        );
+        assert_eq!(tree.get(b"a").unwrap(), b"a1");
+        assert!(tree.prove(b"b").is_ok());
+
+        let err = tree.update(b"c", b"c1").unwrap_err();
+        match &err {
+            SmtError::Storage(e) => match e.downcast_ref::<std::io::Error>() {
+                Some(e) => assert_eq!(e.kind(), std::io::ErrorKind::PermissionDenied),
+                None => panic!("expected an I/O error, got {}", e),
+            },
+            _ => panic!("expected a storage error, got {}", err),
+        }
+        assert_eq!(tree.get_root(), root);
     }
 }
@@ -0,0 +1,179 @@
+//!
+//! Deep sparse Merkle subtrees
+//!
+//! A partial tree rebuilt from proofs against a trusted root. It holds only the
+//! nodes on the proven paths, which is enough to update those keys and compute
+//! the new root without the full store.
+//!
+
+use std::collections::HashSet;
+
+use crate::error::{Result, SmtError};
+use crate::hasher::{Blake2sHasher, TreeHasher};
+use crate::proof::{verify_proof, SparseMerkleProof};
+use crate::store::{MemoryStore, TreeStore};
+use crate::tree::SparseMerkleTree;
+use crate::types::{HashValue, Node, DEFAULT_VALUE};
+
+/// A tree holding only the paths of the keys added with `add_branch`
+pub struct DeepSparseMerkleSubTree<H: TreeHasher = Blake2sHasher> {
+    tree: SparseMerkleTree<MemoryStore, H>,
+    covered: HashSet<HashValue>,
+}
+
+impl<H: TreeHasher> DeepSparseMerkleSubTree<H> {
+    pub fn new(root: HashValue) -> Self {
+        Self {
+            tree: SparseMerkleTree::with_store(MemoryStore::new(), Some(root)),
+            covered: HashSet::new(),
+        }
+    }
+
+    pub fn get_root(&self) -> HashValue {
+        self.tree.get_root()
+    }
+
+    /// Add the path of `key` from a proof against the current root. `value` is
+    /// the key's value, or `DEFAULT_VALUE` for a non-membership proof. Deleting
+    /// the key later needs an updatable proof.
+    pub fn add_branch(
+        &mut self,
+        proof: &SparseMerkleProof<H>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        let root = self.get_root();
+        ensure!(
+            verify_proof(proof, root, key, value),
+            SmtError::InvalidProof("proof doesn't match the root")
+        );
+
+        let path = H::digest(key);
+        let store = self.tree.store_mut();
+        let mut current = if value != DEFAULT_VALUE {
+            let leaf = set_node::<H>(store, Node::new_leaf(path, H::digest(value)))?;
+            store.set_value(leaf, value)?;
+            leaf
+        } else if let Some(data) = &proof.non_membership_leaf_data {
+            set_node::<H>(store, Node::decode(data)?)?
+        } else {
+            HashValue::placeholder()
+        };
+
+        let len = proof.sidenodes.len();
+        for (i, sidenode) in proof.sidenodes.iter().enumerate() {
+            let node = match path.has_bit_set(len - 1 - i) {
+                // go right
+                true => Node::new_internal(*sidenode, current),
+                _ => Node::new_internal(current, *sidenode),
+            };
+            current = set_node::<H>(store, node)?;
+        }
+
+        // Checked against the first sidenode by `verify_proof`
+        if let Some(data) = &proof.sibling_data {
+            set_node::<H>(store, Node::decode(data)?)?;
+        }
+
+        self.covered.insert(path);
+        Ok(())
+    }
+
+    /// Get the value of a covered key
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        self.check_covered(key)?;
+        self.tree.get(key)
+    }
+
+    /// Set the value of a covered key, failing with `SmtError::PathNotCovered`
+    /// for keys that weren't added with `add_branch`
+    pub fn update(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_covered(key)?;
+        self.tree.update(key, value)
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.update(key, DEFAULT_VALUE)
+    }
+
+    fn check_covered(&self, key: &[u8]) -> Result<()> {
+        let path = H::digest(key);
+        ensure!(self.covered.contains(&path), SmtError::PathNotCovered(path));
+        Ok(())
+    }
+}
+
+fn set_node<H: TreeHasher>(store: &mut MemoryStore, node: Node) -> Result<HashValue> {
+    let hash = node.hash::<H>();
+    store.set_node(hash, node)?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::build_tree;
+
+    #[test]
+    fn test_deep_subtree() {
+        let mut tree = build_tree(64);
+        let mut deep = DeepSparseMerkleSubTree::new(tree.get_root());
+
+        // members and non-members
+        let keys: Vec<Vec<u8>> = vec![vec![1], vec![2], vec![3], vec![100], vec![101]];
+        for key in &keys {
+            let proof = tree.prove_updatable(key).unwrap();
+            let value = tree.get(key).unwrap_or_default();
+            assert!(deep.add_branch(&proof, key, &value).is_ok());
+        }
+        assert_eq!(deep.get(&[1]).unwrap(), vec![1]);
+
+        let changes: Vec<(&[u8], &[u8])> = vec![
+            (&[1], b"one"),
+            (&[2], DEFAULT_VALUE),
+            (&[100], b"hundred"),
+            (&[3], DEFAULT_VALUE),
+            (&[1], DEFAULT_VALUE),
+        ];
+        for (key, value) in changes {
+            assert!(tree.update(key, value).is_ok());
+            assert!(deep.update(key, value).is_ok());
+            assert_eq!(deep.get_root(), tree.get_root());
+        }
+        assert!(deep.delete(&[101]).is_ok());
+        assert_eq!(deep.get_root(), tree.get_root());
+        assert_eq!(deep.get(&[100]).unwrap(), b"hundred");
+    }
+
+    #[test]
+    fn test_not_covered() {
+        let tree = build_tree(16);
+        let root = tree.get_root();
+        let mut deep = DeepSparseMerkleSubTree::new(root);
+        assert!(deep
+            .add_branch(&tree.prove_updatable(&[1]).unwrap(), &[1], &[1])
+            .is_ok());
+
+        let path = HashValue::digest_of(&[2]);
+        match deep.update(&[2], b"two") {
+            Err(SmtError::PathNotCovered(p)) => assert_eq!(p, path),
+            _ => panic!("expected an uncovered path"),
+        }
+        assert!(matches!(deep.get(&[2]), Err(SmtError::PathNotCovered(_))));
+        assert!(matches!(
+            deep.delete(&[2]),
+            Err(SmtError::PathNotCovered(_))
+        ));
+        assert_eq!(deep.get_root(), root);
+
+        // proofs must match the root and the claimed value
+        let proof = tree.prove_updatable(&[2]).unwrap();
+        assert!(matches!(
+            deep.add_branch(&proof, &[2], b"wrong"),
+            Err(SmtError::InvalidProof(_))
+        ));
+        let other = build_tree(17);
+        let proof = other.prove_updatable(&[2]).unwrap();
+        assert!(deep.add_branch(&proof, &[2], &[2]).is_err());
+    }
+}
@@ -0,0 +1,492 @@
+//!
+//! State sync
+//!
+//! A tree is exported as chunks, each a complete subtree plus the sidenodes
+//! linking it to the root. A chunk can be checked on its own, so chunks can be
+//! fetched from untrusted peers and imported in any order. Internal nodes above
+//! the chunks are rebuilt from the sidenodes.
+//!
+
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+use crate::encoding::Reader;
+use crate::error::{Result, SmtError};
+use crate::hasher::TreeHasher;
+use crate::store::{value_key, TreeStore};
+use crate::tree::SparseMerkleTree;
+use crate::types::{EncodedNode, HashValue, Node};
+
+/// A subtree of an exported tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Depth of the subtree root
+    pub depth: usize,
+    /// The first `depth` bits locate the subtree, the rest are zero
+    pub path: HashValue,
+    /// Siblings of the subtree root and its ancestors, deepest first
+    pub sidenodes: Vec<HashValue>,
+    /// The encoded nodes of the subtree in depth-first order, subtree root first
+    pub nodes: Vec<EncodedNode>,
+    /// The values of the subtree's leaves, in the same order as the leaves
+    pub values: Vec<Vec<u8>>,
+}
+
+impl Chunk {
+    /// `depth (u32) | path | num_sidenodes (u32) | sidenodes | num_nodes (u32) | nodes |
+    /// num_values (u32) | per value: length (u32), bytes`, integers big endian
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(&(self.depth as u32).to_be_bytes());
+        out.extend(self.path.as_ref());
+        out.extend(&(self.sidenodes.len() as u32).to_be_bytes());
+        for sidenode in &self.sidenodes {
+            out.extend(sidenode.as_ref());
+        }
+        out.extend(&(self.nodes.len() as u32).to_be_bytes());
+        for node in &self.nodes {
+            out.extend(node);
+        }
+        out.extend(&(self.values.len() as u32).to_be_bytes());
+        for value in &self.values {
+            out.extend(&(value.len() as u32).to_be_bytes());
+            out.extend(value);
+        }
+        out
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        let mut reader = Reader(raw);
+        let depth = reader.take_u32()? as usize;
+        let path = reader.take_hash()?;
+
+        // Counts aren't trusted for allocation, the input runs out first
+        let mut sidenodes = Vec::new();
+        for _ in 0..reader.take_u32()? {
+            sidenodes.push(reader.take_hash()?);
+        }
+        let mut nodes = Vec::new();
+        for _ in 0..reader.take_u32()? {
+            let node = reader.take_node()?;
+            Node::<32>::decode(&node)?;
+            nodes.push(node);
+        }
+        let mut values = Vec::new();
+        for _ in 0..reader.take_u32()? {
+            let len = reader.take_u32()? as usize;
+            values.push(reader.take(len)?.to_vec());
+        }
+        reader.finish()?;
+
+        Ok(Self {
+            depth,
+            path,
+            sidenodes,
+            nodes,
+            values,
+        })
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> SparseMerkleTree<S, H> {
+    /// Split the tree under `root` into chunks of at most `max_nodes` nodes.
+    /// Internal nodes that don't fit in any chunk are left out, since the
+    /// importer rebuilds them from the sidenodes.
+    pub fn export_chunks(&self, root: HashValue, max_nodes: usize) -> Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        if !root.is_placeholder() {
+            let mut sidenodes = Vec::new();
+            self.export_subtree(
+                root,
+                0,
+                HashValue::placeholder(),
+                &mut sidenodes,
+                max_nodes.max(1),
+                &mut chunks,
+            )?;
+        }
+        Ok(chunks)
+    }
+
+    fn export_subtree(
+        &self,
+        hash: HashValue,
+        depth: usize,
+        path: HashValue,
+        sidenodes: &mut Vec<HashValue>,
+        max_nodes: usize,
+        chunks: &mut Vec<Chunk>,
+    ) -> Result<()> {
+        let mut nodes = Vec::new();
+        let mut values = Vec::new();
+        if self.collect_chunk(hash, max_nodes, &mut nodes, &mut values)? {
+            chunks.push(Chunk {
+                depth,
+                path,
+                sidenodes: sidenodes.iter().rev().copied().collect(),
+                nodes,
+                values,
+            });
+            return Ok(());
+        }
+
+        // Too big, so it can't be a single leaf
+        let (left, right) = match self.get_node(hash)? {
+            Node::Internal(children) => children,
+            Node::Leaf(_) => return Err(SmtError::UnexpectedNodeKind),
+        };
+        for (child, sibling, bit) in [(left, right, false), (right, left, true)] {
+            if child.is_placeholder() {
+                continue;
+            }
+            sidenodes.push(sibling);
+            let child_path = path.with_bit(depth, bit);
+            self.export_subtree(child, depth + 1, child_path, sidenodes, max_nodes, chunks)?;
+            sidenodes.pop();
+        }
+        Ok(())
+    }
+
+    /// Collect the subtree under `hash`, returning false if it has more than `max_nodes` nodes
+    fn collect_chunk(
+        &self,
+        hash: HashValue,
+        max_nodes: usize,
+        nodes: &mut Vec<EncodedNode>,
+        values: &mut Vec<Vec<u8>>,
+    ) -> Result<bool> {
+        if nodes.len() == max_nodes {
+            return Ok(false);
+        }
+        let node = self.get_node(hash)?;
+        nodes.push(node.to_bytes());
+        match node {
+            Node::Leaf((path, value_hash)) => values.push(self.get_value(path, value_hash)?),
+            Node::Internal((left, right)) => {
+                for child in [left, right] {
+                    if !child.is_placeholder()
+                        && !self.collect_chunk(child, max_nodes, nodes, values)?
+                    {
+                        return Ok(false);
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Rebuilds a tree from chunks checked against a trusted root
+pub struct ChunkImporter<S: TreeStore, H: TreeHasher> {
+    store: S,
+    root: HashValue,
+    /// Nodes referenced by imported nodes but not imported yet
+    missing: HashSet<HashValue>,
+    hasher: PhantomData<H>,
+}
+
+impl<S: TreeStore, H: TreeHasher> ChunkImporter<S, H> {
+    /// Start importing the tree under `root` into `store`, which should be empty
+    pub fn new(store: S, root: HashValue) -> Self {
+        let mut missing = HashSet::new();
+        if !root.is_placeholder() {
+            missing.insert(root);
+        }
+        Self {
+            store,
+            root,
+            missing,
+            hasher: PhantomData,
+        }
+    }
+
+    /// Check `chunk` against the root and store it. Nothing is written if the check fails.
+    pub fn import(&mut self, chunk: &Chunk) -> Result<()> {
+        ensure!(
+            chunk.sidenodes.len() == chunk.depth && chunk.depth <= HashValue::<32>::DEPTH,
+            SmtError::InvalidChunk("wrong number of sidenodes")
+        );
+
+        let mut decoded = Vec::with_capacity(chunk.nodes.len());
+        let mut nodes = Vec::with_capacity(chunk.nodes.len());
+        for raw in &chunk.nodes {
+            let node = Node::decode(raw).map_err(|_| SmtError::InvalidChunk("corrupt node"))?;
+            nodes.push(node.hash::<H>());
+            decoded.push(node);
+        }
+        let subtree_root = *nodes
+            .first()
+            .ok_or(SmtError::InvalidChunk("chunk is empty"))?;
+
+        // The nodes must be exactly the subtree, in depth-first order
+        let mut leaves = Vec::new();
+        let mut next = 0;
+        check_subtree(
+            subtree_root,
+            chunk.depth,
+            &nodes,
+            &decoded,
+            &mut next,
+            &mut leaves,
+        )?;
+        ensure!(
+            next == nodes.len(),
+            SmtError::InvalidChunk("nodes outside the subtree")
+        );
+
+        ensure!(
+            leaves.len() == chunk.values.len(),
+            SmtError::InvalidChunk("wrong number of values")
+        );
+        for ((path, value_hash), value) in leaves.iter().zip(&chunk.values) {
+            ensure!(
+                path.common_prefix_bits_len(chunk.path) >= chunk.depth,
+                SmtError::InvalidChunk("leaf outside the subtree")
+            );
+            ensure!(
+                H::digest(value) == *value_hash,
+                SmtError::InvalidChunk("value doesn't match its leaf")
+            );
+        }
+
+        // Hash up to the root, keeping the internal nodes on the way
+        let mut ancestors = Vec::with_capacity(chunk.depth);
+        let mut current = subtree_root;
+        for (i, sidenode) in chunk.sidenodes.iter().enumerate() {
+            let node = match chunk.path.has_bit_set(chunk.depth - 1 - i) {
+                // go right
+                true => Node::new_internal(*sidenode, current),
+                _ => Node::new_internal(current, *sidenode),
+            };
+            current = node.hash::<H>();
+            ancestors.push((current, node));
+        }
+        ensure!(
+            current == self.root,
+            SmtError::InvalidChunk("chunk doesn't match the root")
+        );
+
+        let written: Vec<(HashValue, Node)> =
+            nodes.into_iter().zip(decoded).chain(ancestors).collect();
+        for (hash, node) in &written {
+            self.store.set_node(*hash, *node)?;
+        }
+        for ((path, value_hash), value) in leaves.iter().zip(&chunk.values) {
+            self.store
+                .set_value(value_key::<H, 32>(*path, *value_hash), value)?;
+        }
+
+        for (hash, _) in &written {
+            self.missing.remove(hash);
+        }
+        for (_, node) in &written {
+            if let Node::Internal((left, right)) = node {
+                for child in [left, right] {
+                    if !child.is_placeholder() && self.store.get_node(*child)?.is_none() {
+                        self.missing.insert(*child);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// True once every node under the root has been imported
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    /// Commit the root to the store and open the tree, failing with
+    /// `SmtError::MissingNode` if the import isn't complete
+    pub fn finish(mut self) -> Result<SparseMerkleTree<S, H>> {
+        if let Some(hash) = self.missing.iter().next() {
+            return Err(SmtError::MissingNode(*hash));
+        }
+        self.store.set_root(self.root)?;
+        Ok(SparseMerkleTree::with_store(self.store, Some(self.root)))
+    }
+}
+
+/// Walk the chunk nodes from `next` as the subtree `hash` at `depth`,
+/// collecting its leaves. Nothing can sit below the full path width, which
+/// also bounds the recursion.
+fn check_subtree(
+    hash: HashValue,
+    depth: usize,
+    hashes: &[HashValue],
+    nodes: &[Node],
+    next: &mut usize,
+    leaves: &mut Vec<(HashValue, HashValue)>,
+) -> Result<()> {
+    ensure!(
+        depth <= HashValue::<32>::DEPTH,
+        SmtError::InvalidChunk("subtree is too deep")
+    );
+    ensure!(
+        *next < nodes.len() && hashes[*next] == hash,
+        SmtError::InvalidChunk("incomplete subtree")
+    );
+    let node = nodes[*next];
+    *next += 1;
+    match node {
+        Node::Leaf(leaf) => leaves.push(leaf),
+        Node::Internal((left, right)) => {
+            for child in [left, right] {
+                if !child.is_placeholder() {
+                    check_subtree(child, depth + 1, hashes, nodes, next, leaves)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::build_tree;
+    use crate::{Blake2sHasher, MemoryStore};
+    use rand::seq::SliceRandom;
+
+    fn new_importer(root: HashValue) -> ChunkImporter<MemoryStore, Blake2sHasher> {
+        ChunkImporter::new(MemoryStore::new(), root)
+    }
+
+    #[test]
+    fn test_export_import() {
+        let tree = build_tree(250);
+        let root = tree.get_root();
+
+        for max_nodes in [1, 7, 64, 10_000] {
+            let mut chunks = tree.export_chunks(root, max_nodes).unwrap();
+            assert!(chunks.iter().all(|c| c.nodes.len() <= max_nodes));
+            chunks.shuffle(&mut rand::thread_rng());
+
+            let mut importer = new_importer(root);
+            for chunk in &chunks {
+                assert!(!importer.is_complete());
+                assert!(importer.import(chunk).is_ok());
+            }
+            assert!(importer.is_complete());
+
+            let synced = importer.finish().unwrap();
+            assert_eq!(synced.get_root(), root);
+            for i in 0..250u8 {
+                assert_eq!(synced.get(&[i]).unwrap(), vec![i]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let tree = build_tree(50);
+        let root = tree.get_root();
+        let chunks = tree.export_chunks(root, 8).unwrap();
+
+        let mut importer = new_importer(root);
+        for chunk in &chunks[1..] {
+            assert!(importer.import(chunk).is_ok());
+        }
+        assert!(!importer.is_complete());
+        assert!(matches!(importer.finish(), Err(SmtError::MissingNode(_))));
+
+        // an empty tree needs no chunks
+        let empty = HashValue::placeholder();
+        assert!(tree.export_chunks(empty, 8).unwrap().is_empty());
+        assert!(new_importer(empty).is_complete());
+    }
+
+    #[test]
+    fn test_bad_chunks() {
+        let tree = build_tree(50);
+        let root = tree.get_root();
+        let chunk = tree.export_chunks(root, 8).unwrap().remove(0);
+        let mut importer = new_importer(root);
+
+        let bad = |f: &dyn Fn(&mut Chunk)| {
+            let mut c = chunk.clone();
+            f(&mut c);
+            c
+        };
+        let bad_chunks = vec![
+            bad(&|c| c.values[0].push(1)),
+            bad(&|c| {
+                c.values.pop();
+            }),
+            bad(&|c| c.nodes[0][40] ^= 1),
+            bad(&|c| {
+                c.nodes.pop();
+            }),
+            bad(&|c| c.nodes.push(c.nodes[0].clone())),
+            bad(&|c| c.sidenodes[0] = HashValue::digest_of(b"x")),
+            bad(&|c| {
+                c.sidenodes.pop();
+            }),
+            bad(&|c| c.path = c.path.with_bit(0, !c.path.has_bit_set(0))),
+        ];
+        for c in &bad_chunks {
+            assert!(matches!(importer.import(c), Err(SmtError::InvalidChunk(_))));
+        }
+        // nothing was written
+        assert!(importer.store.get_node(root).unwrap().is_none());
+
+        // chunks for a different root are rejected too
+        let mut other = new_importer(build_tree(51).get_root());
+        assert!(matches!(
+            other.import(&chunk),
+            Err(SmtError::InvalidChunk(_))
+        ));
+        assert!(importer.import(&chunk).is_ok());
+    }
+
+    #[test]
+    fn test_deep_chunk() {
+        // a chain of internal nodes far below the path width
+        let mut node = Node::new_leaf(HashValue::digest_of(b"a"), HashValue::digest_of(b""));
+        let mut nodes = vec![node.to_bytes()];
+        for _ in 0..50_000 {
+            node = Node::new_internal(node.hash::<Blake2sHasher>(), HashValue::placeholder());
+            nodes.push(node.to_bytes());
+        }
+        nodes.reverse();
+        let chunk = Chunk {
+            depth: 0,
+            path: HashValue::placeholder(),
+            sidenodes: vec![],
+            nodes,
+            values: vec![vec![]],
+        };
+
+        let mut importer = new_importer(node.hash::<Blake2sHasher>());
+        assert!(matches!(
+            importer.import(&chunk),
+            Err(SmtError::InvalidChunk("subtree is too deep"))
+        ));
+    }
+
+    #[test]
+    fn test_chunk_bytes() {
+        let tree = build_tree(50);
+        let root = tree.get_root();
+        let chunks = tree.export_chunks(root, 8).unwrap();
+
+        let mut importer = new_importer(root);
+        for chunk in &chunks {
+            let raw = chunk.to_bytes();
+            let decoded = Chunk::from_bytes(&raw).unwrap();
+            assert_eq!(&decoded, chunk);
+            assert!(importer.import(&decoded).is_ok());
+
+            for len in 0..raw.len() {
+                assert!(Chunk::from_bytes(&raw[..len]).is_err());
+            }
+        }
+        assert!(importer.finish().is_ok());
+
+        // a node that doesn't decode
+        let mut raw = chunks[0].to_bytes();
+        let at = 4 + 32 + 4 + 32 * chunks[0].depth + 4;
+        raw[at] = 7;
+        assert!(Chunk::from_bytes(&raw).is_err());
+    }
+}
@@ -0,0 +1,356 @@
+//!
+//! Transactions
+//!
+//! An in-memory overlay over a tree's store. Writes are buffered in layers,
+//! one per open savepoint, and only reach the store on `commit`.
+//!
+
+use std::collections::HashMap;
+
+use crate::error::Result;
+use crate::hasher::TreeHasher;
+use crate::store::{MaybeSync, TreeStore};
+use crate::tree::SparseMerkleTree;
+use crate::types::{HashValue, Node, DEFAULT_VALUE};
+
+/// Writes buffered since a savepoint. `None` marks a deletion.
+struct Layer {
+    /// Id of the savepoint that opened the layer, 0 for the transaction's own
+    id: usize,
+    /// Root when the layer was opened
+    root: HashValue,
+    nodes: HashMap<HashValue, Option<Node>>,
+    values: HashMap<HashValue, Option<Vec<u8>>>,
+}
+
+impl Layer {
+    fn new(id: usize, root: HashValue) -> Self {
+        Self {
+            id,
+            root,
+            nodes: HashMap::new(),
+            values: HashMap::new(),
+        }
+    }
+
+    fn merge(&mut self, above: Layer) {
+        self.nodes.extend(above.nodes);
+        self.values.extend(above.values);
+    }
+}
+
+/// Reads through the layers, newest first, then the base store. Writes go to
+/// the newest layer.
+struct Overlay<'a, S: TreeStore> {
+    base: &'a S,
+    layers: &'a mut Vec<Layer>,
+}
+
+impl<'a, S: TreeStore> Overlay<'a, S> {
+    fn top(&mut self) -> &mut Layer {
+        // A transaction always has its own layer at the bottom
+        self.layers.last_mut().expect("transaction without layers")
+    }
+}
+
+impl<'a, S: TreeStore> TreeStore for Overlay<'a, S> {
+    fn get_node(&self, key: HashValue) -> Result<Option<Node>> {
+        match self.layers.iter().rev().find_map(|l| l.nodes.get(&key)) {
+            Some(node) => Ok(*node),
+            None => self.base.get_node(key),
+        }
+    }
+
+    fn set_node(&mut self, key: HashValue, node: Node) -> Result<()> {
+        self.top().nodes.insert(key, Some(node));
+        Ok(())
+    }
+
+    fn delete_node(&mut self, key: &HashValue) -> Result<()> {
+        self.top().nodes.insert(*key, None);
+        Ok(())
+    }
+
+    fn get_value(&self, key: HashValue) -> Result<Option<Vec<u8>>> {
+        match self.layers.iter().rev().find_map(|l| l.values.get(&key)) {
+            Some(value) => Ok(value.clone()),
+            None => self.base.get_value(key),
+        }
+    }
+
+    fn set_value(&mut self, key: HashValue, value: &[u8]) -> Result<()> {
+        self.top().values.insert(key, Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn delete_value(&mut self, key: &HashValue) -> Result<()> {
+        self.top().values.insert(*key, None);
+        Ok(())
+    }
+}
+
+/// A point to roll a transaction back to, from `Transaction::savepoint`.
+/// Each one has its own id, so a closed savepoint never matches a newer one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Savepoint(usize);
+
+/// Buffered changes on top of a tree, from `SparseMerkleTree::begin`.
+///
+/// Nothing reaches the store until `commit`. Dropping the transaction or
+/// calling `rollback` discards everything.
+pub struct Transaction<'a, S: TreeStore, H: TreeHasher> {
+    tree: &'a mut SparseMerkleTree<S, H>,
+    root: HashValue,
+    layers: Vec<Layer>,
+    /// Id of the next savepoint
+    next_id: usize,
+}
+
+impl<S: TreeStore, H: TreeHasher> SparseMerkleTree<S, H> {
+    /// Start a transaction at the current root
+    pub fn begin(&mut self) -> Transaction<'_, S, H> {
+        let root = self.get_root();
+        Transaction {
+            tree: self,
+            root,
+            layers: vec![Layer::new(0, root)],
+            next_id: 1,
+        }
+    }
+}
+
+impl<'a, S: TreeStore, H: TreeHasher> Transaction<'a, S, H> {
+    /// The root with every change so far applied
+    pub fn root(&self) -> HashValue {
+        self.root
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Vec<u8>> {
+        self.with_overlay(|tree| tree.get(key))
+    }
+
+    pub fn update(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let root = self.root;
+        self.root = self.with_overlay(|tree| tree.update_for_root(key, value, root))?;
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.update(key, DEFAULT_VALUE)
+    }
+
+    /// Apply many updates at once, as `SparseMerkleTree::update_batch` does
+    pub fn update_batch<K, V, I>(&mut self, entries: I) -> Result<()>
+    where
+        S: MaybeSync,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let root = self.root;
+        self.root = self.with_overlay(|tree| tree.update_batch_for_root(entries, root))?;
+        Ok(())
+    }
+
+    /// Open a nested savepoint. Changes made after it can be undone with
+    /// `rollback_to` without losing earlier ones.
+    pub fn savepoint(&mut self) -> Savepoint {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.layers.push(Layer::new(id, self.root));
+        Savepoint(id)
+    }
+
+    /// Undo every change since `savepoint`, closing it and any savepoints
+    /// opened after it. Does nothing if it's already closed.
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        if let Some(index) = self.layer_index(savepoint) {
+            self.root = self.layers[index].root;
+            self.layers.truncate(index);
+        }
+    }
+
+    /// Close `savepoint` and any savepoints opened after it, keeping their
+    /// changes. Does nothing if it's already closed.
+    pub fn release(&mut self, savepoint: Savepoint) {
+        if let Some(index) = self.layer_index(savepoint) {
+            while self.layers.len() > index {
+                if let Some(layer) = self.layers.pop() {
+                    self.top().merge(layer);
+                }
+            }
+        }
+    }
+
+    /// Write the buffered changes to the store and commit the new root.
+    /// If a write fails the tree keeps its old root.
+    pub fn commit(mut self) -> Result<HashValue> {
+        let mut changes = Layer::new(0, self.root);
+        for layer in self.layers.drain(..) {
+            changes.merge(layer);
+        }
+
+        let store = self.tree.store_mut();
+        for (key, node) in changes.nodes {
+            match node {
+                Some(node) => store.set_node(key, node)?,
+                None => store.delete_node(&key)?,
+            }
+        }
+        for (key, value) in changes.values {
+            match value {
+                Some(value) => store.set_value(key, &value)?,
+                None => store.delete_value(&key)?,
+            }
+        }
+
+        store.set_root(self.root)?;
+        self.tree.set_root(self.root);
+        Ok(self.root)
+    }
+
+    /// Drop every buffered change
+    pub fn rollback(self) {}
+
+    fn top(&mut self) -> &mut Layer {
+        self.layers.last_mut().expect("transaction without layers")
+    }
+
+    /// Position of the layer `savepoint` opened, if it's still open
+    fn layer_index(&self, savepoint: Savepoint) -> Option<usize> {
+        // The transaction's own layer at the bottom can't be closed
+        self.layers
+            .iter()
+            .skip(1)
+            .position(|layer| layer.id == savepoint.0)
+            .map(|index| index + 1)
+    }
+
+    fn with_overlay<T>(
+        &mut self,
+        f: impl FnOnce(&mut SparseMerkleTree<Overlay<'_, S>, H>) -> Result<T>,
+    ) -> Result<T> {
+        let overlay = Overlay {
+            base: self.tree.store(),
+            layers: &mut self.layers,
+        };
+        f(&mut SparseMerkleTree::with_store(overlay, Some(self.root)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::build_tree;
+    use crate::SmtError;
+
+    #[test]
+    fn test_commit() {
+        let mut tree = build_tree(20);
+        let mut expected = build_tree(20);
+        for (key, value) in [
+            (&[1u8][..], &b"one"[..]),
+            (&[2], DEFAULT_VALUE),
+            (&[50], b"new"),
+        ] {
+            assert!(expected.update(key, value).is_ok());
+        }
+
+        let mut tx = tree.begin();
+        assert!(tx.update(&[1], b"one").is_ok());
+        assert!(tx.delete(&[2]).is_ok());
+        assert!(tx.update_batch(vec![(vec![50u8], b"new".to_vec())]).is_ok());
+        assert_eq!(tx.root(), expected.get_root());
+        assert_eq!(tx.get(&[1]).unwrap(), b"one");
+        assert!(matches!(tx.get(&[2]), Err(SmtError::KeyNotFound)));
+
+        assert_eq!(tx.commit().unwrap(), expected.get_root());
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert_eq!(tree.store().get_root().unwrap(), Some(expected.get_root()));
+        assert_eq!(tree.get(&[50]).unwrap(), b"new");
+    }
+
+    #[test]
+    fn test_rollback() {
+        let mut tree = build_tree(20);
+        let root = tree.get_root();
+
+        let mut tx = tree.begin();
+        assert!(tx.update(&[1], b"one").is_ok());
+        assert!(tx.update(&[60], b"sixty").is_ok());
+        assert_ne!(tx.root(), root);
+        tx.rollback();
+
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.get(&[1]).unwrap(), vec![1]);
+        assert!(matches!(tree.get(&[60]), Err(SmtError::KeyNotFound)));
+        assert_eq!(tree.store().value_keys().unwrap().len(), 20);
+
+        // dropping is a rollback too
+        {
+            let mut tx = tree.begin();
+            assert!(tx.delete(&[3]).is_ok());
+        }
+        assert_eq!(tree.get_root(), root);
+    }
+
+    #[test]
+    fn test_savepoints() {
+        let mut tree = build_tree(10);
+        let mut expected = build_tree(10);
+        assert!(expected.update(&[1], b"a").is_ok());
+        assert!(expected.update(&[3], b"c").is_ok());
+
+        let mut tx = tree.begin();
+        assert!(tx.update(&[1], b"a").is_ok());
+
+        // a reverted tx
+        let outer = tx.savepoint();
+        assert!(tx.update(&[2], b"b").is_ok());
+        let inner = tx.savepoint();
+        assert!(tx.delete(&[1]).is_ok());
+        tx.rollback_to(inner);
+        assert_eq!(tx.get(&[1]).unwrap(), b"a");
+        tx.rollback_to(outer);
+        assert_eq!(tx.get(&[2]).unwrap(), vec![2]);
+
+        // a kept tx, with a nested savepoint released into it
+        let kept = tx.savepoint();
+        let nested = tx.savepoint();
+        assert!(tx.update(&[3], b"c").is_ok());
+        tx.release(nested);
+        tx.release(kept);
+        // already closed
+        tx.rollback_to(nested);
+        assert_eq!(tx.root(), expected.get_root());
+
+        assert!(tx.commit().is_ok());
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert_eq!(tree.get(&[2]).unwrap(), vec![2]);
+        assert_eq!(tree.get(&[3]).unwrap(), b"c");
+    }
+
+    #[test]
+    fn test_closed_savepoints() {
+        let mut tree = build_tree(10);
+        let mut tx = tree.begin();
+
+        // a released savepoint doesn't match one opened later at the same depth
+        let released = tx.savepoint();
+        assert!(tx.update(&[1], b"a").is_ok());
+        tx.release(released);
+        let open = tx.savepoint();
+        assert_ne!(open, released);
+        assert!(tx.update(&[2], b"b").is_ok());
+        let root = tx.root();
+
+        tx.rollback_to(released);
+        tx.release(released);
+        assert_eq!(tx.root(), root);
+        assert_eq!(tx.get(&[2]).unwrap(), b"b");
+
+        tx.rollback_to(open);
+        assert_eq!(tx.get(&[1]).unwrap(), b"a");
+        assert_eq!(tx.get(&[2]).unwrap(), vec![2]);
+    }
+}
@@ -0,0 +1,236 @@
+//!
+//! State transition proofs
+//!
+//! A witness holds the nodes of the old tree that applying a changeset reads.
+//! That is enough for a verifier without a store to replay the changeset and
+//! check the new root.
+//!
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::error::{Result, SmtError};
+use crate::hasher::TreeHasher;
+use crate::store::{MemoryStore, TreeStore};
+use crate::tree::SparseMerkleTree;
+use crate::types::{EncodedNode, HashValue, Node};
+
+const ENCODED_NODE_LENGTH: usize = Node::<32>::ENCODED_LENGTH;
+
+/// The old-tree nodes needed to replay a changeset
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TransitionWitness {
+    /// Encoded nodes, ordered by hash
+    pub nodes: Vec<EncodedNode>,
+}
+
+impl TransitionWitness {
+    /// `count (u32, big endian) | nodes`
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.nodes.len() * ENCODED_NODE_LENGTH);
+        out.extend(&(self.nodes.len() as u32).to_be_bytes());
+        for node in &self.nodes {
+            out.extend(node);
+        }
+        out
+    }
+
+    pub fn from_bytes(raw: &[u8]) -> Result<Self> {
+        ensure!(
+            raw.len() >= 4,
+            SmtError::InvalidEncoding("unexpected end of input")
+        );
+        let (count, rest) = raw.split_at(4);
+        let count = u32::from_be_bytes([count[0], count[1], count[2], count[3]]) as usize;
+        ensure!(
+            rest.len() as u64 == count as u64 * ENCODED_NODE_LENGTH as u64,
+            SmtError::InvalidEncoding("wrong witness length")
+        );
+
+        let nodes = rest
+            .chunks(ENCODED_NODE_LENGTH)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        Ok(Self { nodes })
+    }
+}
+
+/// Reads through to a base store, writes to an overlay, and records every
+/// node read from the base
+struct RecordingStore<'a, S: TreeStore> {
+    base: &'a S,
+    overlay: MemoryStore,
+    read: RefCell<BTreeMap<HashValue, Node>>,
+}
+
+impl<'a, S: TreeStore> TreeStore for RecordingStore<'a, S> {
+    fn get_node(&self, key: HashValue) -> Result<Option<Node>> {
+        if let Some(node) = self.overlay.get_node(key)? {
+            return Ok(Some(node));
+        }
+        let node = self.base.get_node(key)?;
+        if let Some(node) = node {
+            self.read.borrow_mut().insert(key, node);
+        }
+        Ok(node)
+    }
+
+    fn set_node(&mut self, key: HashValue, node: Node) -> Result<()> {
+        self.overlay.set_node(key, node)
+    }
+
+    fn delete_node(&mut self, key: &HashValue) -> Result<()> {
+        self.overlay.delete_node(key)
+    }
+
+    fn get_value(&self, key: HashValue) -> Result<Option<Vec<u8>>> {
+        match self.overlay.get_value(key)? {
+            Some(value) => Ok(Some(value)),
+            None => self.base.get_value(key),
+        }
+    }
+
+    fn set_value(&mut self, key: HashValue, value: &[u8]) -> Result<()> {
+        self.overlay.set_value(key, value)
+    }
+
+    fn delete_value(&mut self, key: &HashValue) -> Result<()> {
+        self.overlay.delete_value(key)
+    }
+}
+
+impl<S: TreeStore, H: TreeHasher> SparseMerkleTree<S, H> {
+    /// Apply `changes` in order on top of `root` without touching the store,
+    /// returning the new root and a witness for `verify_transition`.
+    /// `DEFAULT_VALUE` deletes a key.
+    pub fn prove_transition<K, V, I>(
+        &self,
+        root: HashValue,
+        changes: I,
+    ) -> Result<(HashValue, TransitionWitness)>
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let store = RecordingStore {
+            base: self.store(),
+            overlay: MemoryStore::new(),
+            read: RefCell::new(BTreeMap::new()),
+        };
+        let mut replay: SparseMerkleTree<_, H> = SparseMerkleTree::with_store(store, Some(root));
+        let mut new_root = root;
+        for (key, value) in changes {
+            new_root = replay.update_for_root(key.as_ref(), value.as_ref(), new_root)?;
+        }
+
+        let read = replay.into_store().read.into_inner();
+        let nodes = read.values().map(|node| node.to_bytes()).collect();
+        Ok((new_root, TransitionWitness { nodes }))
+    }
+}
+
+/// Check that applying `changes` in order to `old_root` gives `new_root`,
+/// using only the nodes in `witness`
+pub fn verify_transition<H, K, V, I>(
+    witness: &TransitionWitness,
+    old_root: HashValue,
+    new_root: HashValue,
+    changes: I,
+) -> bool
+where
+    H: TreeHasher,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+    I: IntoIterator<Item = (K, V)>,
+{
+    // Nodes are stored under their own hash, so a bad node can only be missed, never trusted
+    let mut store = MemoryStore::new();
+    for raw in &witness.nodes {
+        let node = match Node::decode(raw) {
+            Ok(node) => node,
+            Err(_) => return false,
+        };
+        if store.set_node(node.hash::<H>(), node).is_err() {
+            return false;
+        }
+    }
+
+    let mut tree: SparseMerkleTree<_, H> = SparseMerkleTree::with_store(store, Some(old_root));
+    for (key, value) in changes {
+        if tree.update(key.as_ref(), value.as_ref()).is_err() {
+            return false;
+        }
+    }
+    tree.get_root() == new_root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::build_tree;
+    use crate::{Blake2sHasher, DEFAULT_VALUE};
+
+    #[test]
+    fn test_transition() {
+        let mut tree = build_tree(100);
+        let old_root = tree.get_root();
+        let changes: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (vec![1], b"one".to_vec()),
+            (vec![2], DEFAULT_VALUE.to_vec()),
+            (vec![200], b"new".to_vec()),
+            // deleting an absent key and writing a key twice
+            (vec![201], DEFAULT_VALUE.to_vec()),
+            (vec![1], b"uno".to_vec()),
+        ];
+
+        let (new_root, witness) = tree.prove_transition(old_root, changes.clone()).unwrap();
+        // the store is untouched
+        assert_eq!(tree.get(&[1]).unwrap(), vec![1]);
+        assert!(witness.nodes.len() < 100);
+
+        for (key, value) in &changes {
+            assert!(tree.update(key, value).is_ok());
+        }
+        assert_eq!(tree.get_root(), new_root);
+
+        let check = |w: &TransitionWitness, old, new, changes: &[(Vec<u8>, Vec<u8>)]| {
+            verify_transition::<Blake2sHasher, _, _, _>(w, old, new, changes.iter().cloned())
+        };
+        assert!(check(&witness, old_root, new_root, &changes));
+
+        let decoded = TransitionWitness::from_bytes(&witness.to_bytes()).unwrap();
+        assert_eq!(decoded, witness);
+        assert!(check(&decoded, old_root, new_root, &changes));
+
+        // wrong roots or changes
+        assert!(!check(&witness, old_root, old_root, &changes));
+        assert!(!check(&witness, new_root, new_root, &changes));
+        assert!(!check(&witness, old_root, new_root, &changes[..4]));
+        let mut other = changes.clone();
+        other[2].1 = b"other".to_vec();
+        assert!(!check(&witness, old_root, new_root, &other));
+
+        // a missing node makes it unverifiable
+        let mut partial = witness.clone();
+        partial.nodes.pop();
+        assert!(!check(&partial, old_root, new_root, &changes));
+        let mut tampered = witness;
+        tampered.nodes[0][10] ^= 1;
+        assert!(!check(&tampered, old_root, new_root, &changes));
+    }
+
+    #[test]
+    fn test_delete_everything() {
+        let tree = build_tree(5);
+        let old_root = tree.get_root();
+        let changes: Vec<([u8; 1], &[u8])> = (0..5u8).map(|i| ([i], DEFAULT_VALUE)).collect();
+
+        let (new_root, witness) = tree.prove_transition(old_root, changes.clone()).unwrap();
+        assert!(new_root.is_placeholder());
+        assert!(verify_transition::<Blake2sHasher, _, _, _>(
+            &witness, old_root, new_root, changes
+        ));
+        assert!(TransitionWitness::from_bytes(&[0, 0, 0, 1]).is_err());
+    }
+}
@@ -1,37 +1,304 @@
-use anyhow::{anyhow, bail, Result};
+use std::collections::HashMap;
+use std::marker::PhantomData;
 
-use crate::store::MemoryStore;
+use crate::error::{Result, SmtError};
+use crate::hasher::{Blake2sHasher, TreeHasher};
+use crate::iter::Iter;
+use crate::proof::{SparseMerkleMultiProof, SparseMerkleProof};
+use crate::store::{value_key, MaybeSync, MemoryStore, TreeStore};
 use crate::types::{HashValue, Node, DEFAULT_VALUE};
 
-pub struct SparseMerkleTree {
-    root: HashValue,
-    store: MemoryStore,
+/// Smallest batch split between threads with the `parallel` feature
+#[cfg(feature = "parallel")]
+const PARALLEL_BATCH: usize = 64;
+
+/// New nodes collected while applying a batch
+#[derive(Default)]
+struct BatchWrites<const N: usize> {
+    nodes: HashMap<HashValue<N>, Node<N>>,
 }
 
-impl SparseMerkleTree {
+/// (sidenodes, pathnodes, leaf at the end of the path, sibling data)
+type SideNodes<const N: usize> = (
+    Vec<HashValue<N>>,
+    Vec<HashValue<N>>,
+    Option<Node<N>>,
+    Option<Node<N>>,
+);
+
+/// A sparse Merkle tree over a `TreeStore`.
+///
+/// Updates never delete nodes from the store. Nodes are content addressed, so
+/// any number of roots can share one store, and updating from an older root
+/// with `update_for_root` leaves every other root's nodes and values intact.
+///
+/// `N` is the width of the tree's paths and hashes in bytes, which must match
+/// the output of `H`. Everything defaults to 32 byte paths hashed with Blake2s.
+/// Other widths get the core tree, its proofs, `MemoryStore`, `FileStore` and
+/// `CacheStore`; shared stores, sync, diffs, transactions and garbage
+/// collection work on 32 byte trees.
+pub struct SparseMerkleTree<
+    S: TreeStore<N> = MemoryStore,
+    H: TreeHasher<N> = Blake2sHasher,
+    const N: usize = 32,
+> {
+    root: HashValue<N>,
+    store: S,
+    // Hashers are never instantiated, so they don't affect `Send` or `Sync`
+    hasher: PhantomData<fn() -> H>,
+}
+
+impl SparseMerkleTree<MemoryStore> {
     pub fn new(root: Option<HashValue>) -> Self {
+        Self::with_store(MemoryStore::new(), root)
+    }
+}
+
+impl<S: TreeStore<N> + Default, H: TreeHasher<N>, const N: usize> Default
+    for SparseMerkleTree<S, H, N>
+{
+    fn default() -> Self {
+        Self::with_store(S::default(), None)
+    }
+}
+
+impl<S: TreeStore<N>, H: TreeHasher<N>, const N: usize> SparseMerkleTree<S, H, N> {
+    pub fn with_store(store: S, root: Option<HashValue<N>>) -> Self {
         Self {
             root: root.unwrap_or(HashValue::placeholder()),
-            store: MemoryStore::new(),
+            store,
+            hasher: PhantomData,
         }
     }
 
-    pub fn set_root(&mut self, root: HashValue) {
+    /// Open a tree at the root last committed to `store`
+    pub fn load(store: S) -> Result<Self, N> {
+        let root = store.get_root()?;
+        Ok(Self::with_store(store, root))
+    }
+
+    /// Record the current root in the store so `load` can pick it up again
+    pub fn commit(&mut self) -> Result<(), N> {
+        self.store.set_root(self.root)?;
+        Ok(())
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub(crate) fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    pub fn set_root(&mut self, root: HashValue<N>) {
         self.root = root;
     }
 
-    pub fn get_root(&self) -> HashValue {
+    pub fn get_root(&self) -> HashValue<N> {
         self.root
     }
 
-    pub fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
-        if self.root.is_placeholder() {
-            return None;
+    /// Get the value for `key`, failing with `SmtError::KeyNotFound` if it's absent
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>, N> {
+        self.get_for_root(key, self.root)?
+            .ok_or(SmtError::KeyNotFound)
+    }
+
+    /// Get the value committed for `key` under `root`, or `None` if the key
+    /// was absent in that tree
+    pub fn get_for_root(&self, key: &[u8], root: HashValue<N>) -> Result<Option<Vec<u8>>, N> {
+        self.get_path_for_root(H::digest(key), root)
+    }
+
+    /// Like `get`, for a caller that already holds the key's path and wants
+    /// it used as is instead of hashed
+    pub fn get_path(&self, path: HashValue<N>) -> Result<Vec<u8>, N> {
+        self.get_path_for_root(path, self.root)?
+            .ok_or(SmtError::KeyNotFound)
+    }
+
+    pub fn get_path_for_root(
+        &self,
+        path: HashValue<N>,
+        root: HashValue<N>,
+    ) -> Result<Option<Vec<u8>>, N> {
+        let mut current = root;
+
+        for i in 0..=HashValue::<N>::DEPTH {
+            if current.is_placeholder() {
+                return Ok(None);
+            }
+
+            let node = self.get_node(current)?;
+            current = match node {
+                Node::Leaf((actual_path, _)) if actual_path != path => return Ok(None),
+                Node::Leaf((_, value_hash)) => return self.get_value(path, value_hash).map(Some),
+                Node::Internal(_) if i == HashValue::<N>::DEPTH => {
+                    return Err(SmtError::UnexpectedNodeKind)
+                }
+                Node::Internal((left, right)) => match path.has_bit_set(i) {
+                    // go right
+                    true => right,
+                    _ => left,
+                },
+            };
+        }
+
+        Err(SmtError::UnexpectedNodeKind)
+    }
+
+    /// Iterate over the `(path, value)` of every leaf under `root`, in path order
+    pub fn iter(&self, root: HashValue<N>) -> Iter<'_, S, H, N> {
+        Iter::new(
+            self,
+            root,
+            HashValue::new([0u8; N]),
+            HashValue::new([0xffu8; N]),
+        )
+    }
+
+    /// Like `iter`, but only the leaves with `start_path <= path <= end_path`.
+    /// Subtrees entirely outside the range aren't loaded.
+    pub fn range(
+        &self,
+        root: HashValue<N>,
+        start_path: HashValue<N>,
+        end_path: HashValue<N>,
+    ) -> Iter<'_, S, H, N> {
+        Iter::new(self, root, start_path, end_path)
+    }
+
+    /// Generate a membership or non-membership proof for `key` against the current root
+    pub fn prove(&self, key: &[u8]) -> Result<SparseMerkleProof<H, N>, N> {
+        self.prove_for_root(key, self.root)
+    }
+
+    pub fn prove_for_root(
+        &self,
+        key: &[u8],
+        root: HashValue<N>,
+    ) -> Result<SparseMerkleProof<H, N>, N> {
+        self.do_prove_for_root(H::digest(key), root, false)
+    }
+
+    /// Like `prove`, for a path used as is. Check it with `verify_proof_path`.
+    pub fn prove_path(&self, path: HashValue<N>) -> Result<SparseMerkleProof<H, N>, N> {
+        self.do_prove_for_root(path, self.root, false)
+    }
+
+    /// Like `prove`, but also includes the data of the sibling node so the
+    /// proof can be used to update the tree
+    pub fn prove_updatable(&self, key: &[u8]) -> Result<SparseMerkleProof<H, N>, N> {
+        self.prove_updatable_for_root(key, self.root)
+    }
+
+    pub fn prove_updatable_for_root(
+        &self,
+        key: &[u8],
+        root: HashValue<N>,
+    ) -> Result<SparseMerkleProof<H, N>, N> {
+        self.do_prove_for_root(H::digest(key), root, true)
+    }
+
+    fn do_prove_for_root(
+        &self,
+        path: HashValue<N>,
+        root: HashValue<N>,
+        updatable: bool,
+    ) -> Result<SparseMerkleProof<H, N>, N> {
+        let (sidenodes, pathnodes, leaf, sibdata) = self.get_sidenodes(path, root, updatable)?;
+
+        // A placeholder at the end of the path needs nothing else, but a
+        // different leaf there has to be shown to the verifier
+        let mut non_membership_leaf_data = None;
+        if !pathnodes[0].is_placeholder() {
+            match leaf {
+                Some(n @ Node::Leaf((actual_path, _))) if actual_path != path => {
+                    non_membership_leaf_data = Some(n.to_bytes());
+                }
+                Some(Node::Leaf(_)) => {}
+                _ => return Err(SmtError::UnexpectedNodeKind),
+            }
         }
-        self.store.get_value(HashValue::digest_of(key)).ok()
+
+        Ok(SparseMerkleProof::new(
+            sidenodes,
+            non_membership_leaf_data,
+            sibdata.map(|n| n.to_bytes()),
+        ))
+    }
+
+    /// Generate one proof for many keys against the current root
+    pub fn prove_many<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+    ) -> Result<SparseMerkleMultiProof<H, N>, N> {
+        self.prove_many_for_root(keys, self.root)
     }
 
-    pub fn update(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+    pub fn prove_many_for_root<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+        root: HashValue<N>,
+    ) -> Result<SparseMerkleMultiProof<H, N>, N> {
+        let mut paths: Vec<HashValue<N>> = keys.iter().map(|k| H::digest(k.as_ref())).collect();
+        paths.sort();
+        paths.dedup();
+
+        let mut proof = SparseMerkleMultiProof::new(Vec::new(), Vec::new(), Vec::new());
+        if !paths.is_empty() {
+            self.prove_many_subtree(root, 0, &paths, &mut proof)?;
+        }
+        Ok(proof)
+    }
+
+    /// Walk the subtree `hash` at `depth` for the sorted `paths`, in the
+    /// same order `verify_many` rebuilds it
+    fn prove_many_subtree(
+        &self,
+        hash: HashValue<N>,
+        depth: usize,
+        paths: &[HashValue<N>],
+        proof: &mut SparseMerkleMultiProof<H, N>,
+    ) -> Result<(), N> {
+        let node = match hash.is_placeholder() {
+            true => None,
+            false => Some(self.get_node(hash)?),
+        };
+
+        match node {
+            Some(Node::Internal((left, right))) => {
+                ensure!(depth < HashValue::<N>::DEPTH, SmtError::UnexpectedNodeKind);
+                let split = paths.partition_point(|p| !p.has_bit_set(depth));
+                let (l, r) = paths.split_at(split);
+                for (child, paths) in [(left, l), (right, r)] {
+                    match paths.is_empty() {
+                        true => proof.sidenodes.push(child),
+                        false => self.prove_many_subtree(child, depth + 1, paths, proof)?,
+                    }
+                }
+            }
+            leaf => {
+                for path in paths {
+                    proof.depths.push(depth);
+                    proof.leaf_data.push(match leaf {
+                        Some(n @ Node::Leaf((actual_path, _))) if actual_path != *path => {
+                            Some(n.to_bytes())
+                        }
+                        _ => None,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn update(&mut self, key: &[u8], value: &[u8]) -> Result<(), N> {
         let new_root = self.update_for_root(key, value, self.root)?;
         self.set_root(new_root);
         Ok(())
@@ -41,18 +308,35 @@ impl SparseMerkleTree {
         &mut self,
         key: &[u8],
         value: &[u8],
-        root: HashValue,
-    ) -> Result<HashValue> {
-        let path = HashValue::digest_of(key);
+        root: HashValue<N>,
+    ) -> Result<HashValue<N>, N> {
+        self.update_path_for_root(H::digest(key), value, root)
+    }
+
+    /// Like `update`, for a path used as is
+    pub fn update_path(&mut self, path: HashValue<N>, value: &[u8]) -> Result<(), N> {
+        let new_root = self.update_path_for_root(path, value, self.root)?;
+        self.set_root(new_root);
+        Ok(())
+    }
+
+    pub fn delete_path(&mut self, path: HashValue<N>) -> Result<(), N> {
+        self.update_path(path, DEFAULT_VALUE)
+    }
+
+    pub fn update_path_for_root(
+        &mut self,
+        path: HashValue<N>,
+        value: &[u8],
+        root: HashValue<N>,
+    ) -> Result<HashValue<N>, N> {
         let (sidenodes, pathnodes, old_leaf_node, _) = self.get_sidenodes(path, root, false)?;
 
         if value == DEFAULT_VALUE {
+            // A key that is already empty leaves the root as it is
             match self.delete_for_sidenode(path, sidenodes, pathnodes, old_leaf_node) {
-                Ok(r) => {
-                    self.store.delete_value(&path);
-                    Ok(r)
-                }
-                Err(_) => Ok(root),
+                Err(SmtError::KeyNotFound) => Ok(root),
+                result => result,
             }
         } else {
             self.update_with_sidenodes(path, value, sidenodes, pathnodes, old_leaf_node)
@@ -61,162 +345,373 @@ impl SparseMerkleTree {
 
     fn update_with_sidenodes(
         &mut self,
-        path: HashValue,
+        path: HashValue<N>,
         value: &[u8],
-        sidenodes: Vec<HashValue>,
-        pathnodes: Vec<HashValue>,
-        old_leaf_node: Option<Node>,
-    ) -> Result<HashValue> {
-        let value_hash = HashValue::digest_of(value);
+        sidenodes: Vec<HashValue<N>>,
+        pathnodes: Vec<HashValue<N>>,
+        old_leaf_node: Option<Node<N>>,
+    ) -> Result<HashValue<N>, N> {
+        let value_hash = H::digest(value);
         let node = Node::new_leaf(path, value_hash);
 
-        let current_hash = node.encode().and_then(|(h, d)| self.store.set_node(h, d))?;
-        let mut next_hash = current_hash;
+        let leaf_hash = self.set_node(node)?;
+        let mut current_hash = leaf_hash;
 
-        let path_node_root = pathnodes.get(0).ok_or(anyhow!("pathnodes is empty"))?;
+        let path_node_root = pathnodes[0];
 
         let mut old_value_hash = None;
-        let mut common_prefix_count = HashValue::DEPTH;
+        let mut common_prefix_count = HashValue::<N>::DEPTH;
 
         if !path_node_root.is_placeholder() {
-            let n = old_leaf_node.ok_or(anyhow!("old_leaf_data is None"))?;
-            let (actual_path, actual_value_hash) = match n {
-                Node::Leaf(content) => content,
-                _ => bail!("expected leaf"),
+            let (actual_path, actual_value_hash) = match old_leaf_node {
+                Some(Node::Leaf(content)) => content,
+                _ => return Err(SmtError::UnexpectedNodeKind),
             };
             old_value_hash = Some(actual_value_hash);
             common_prefix_count = path.common_prefix_bits_len(actual_path);
         }
 
-        if common_prefix_count != HashValue::DEPTH {
+        if common_prefix_count != HashValue::<N>::DEPTH {
+            // The walk ended on a different leaf, so join the two leaves
+            // under a new internal node at the first bit where they differ.
             let node = match path.has_bit_set(common_prefix_count) {
                 // right
-                true => Node::new_internal(*path_node_root, next_hash),
-                _ => Node::new_internal(next_hash, *path_node_root),
+                true => Node::new_internal(path_node_root, current_hash),
+                _ => Node::new_internal(current_hash, path_node_root),
             };
-
-            let current_hash = node.encode().and_then(|(h, d)| self.store.set_node(h, d))?;
-            next_hash = current_hash;
-        } else if old_value_hash.is_some() {
-            let uovh = old_value_hash.unwrap();
+            current_hash = self.set_node(node)?;
+        } else if let Some(uovh) = old_value_hash {
             if uovh == value_hash {
-                return Ok(self.root);
+                return Ok(pathnodes[pathnodes.len() - 1]);
             }
-            self.store.delete_node(&path_node_root);
-            self.store.delete_value(&path);
         }
 
-        for index in 1..pathnodes.len() {
-            self.store.delete_node(pathnodes.get(index).unwrap());
-        }
-
-        for i in 0..HashValue::DEPTH {
-            let temp = &HashValue::placeholder();
-            let sn = sidenodes.get(i).or_else(|| {
-                if common_prefix_count != HashValue::DEPTH
-                    && common_prefix_count > HashValue::DEPTH - 1 - i
+        // sidenodes only cover the top of the tree, everything below them is
+        // either padded with placeholders or skipped.
+        let offset = HashValue::<N>::DEPTH - sidenodes.len();
+        for i in 0..HashValue::<N>::DEPTH {
+            let sidenode = if i < offset {
+                if common_prefix_count != HashValue::<N>::DEPTH
+                    && common_prefix_count > HashValue::<N>::DEPTH - 1 - i
                 {
-                    Some(temp)
+                    HashValue::placeholder()
                 } else {
-                    None
+                    continue;
                 }
-            });
-            if sn.is_none() {
-                continue;
-            }
+            } else {
+                sidenodes[i - offset]
+            };
 
-            let sidenode = sn.unwrap();
-            let node = match path.has_bit_set(common_prefix_count) {
+            let node = match path.has_bit_set(HashValue::<N>::DEPTH - 1 - i) {
                 // go right
-                true => Node::new_internal(*sidenode, next_hash),
-                _ => Node::new_internal(next_hash, *sidenode),
+                true => Node::new_internal(sidenode, current_hash),
+                _ => Node::new_internal(current_hash, sidenode),
             };
-
-            let current_hash = node.encode().and_then(|(h, d)| self.store.set_node(h, d))?;
-            next_hash = current_hash;
+            current_hash = self.set_node(node)?;
         }
 
-        self.store.set_value(path, value)?;
+        self.store.set_value(leaf_hash, value)?;
         Ok(current_hash)
     }
 
     fn delete_for_sidenode(
         &mut self,
-        path: HashValue,
-        sidenodes: Vec<HashValue>,
-        pathnodes: Vec<HashValue>,
-        old_leaf_node: Option<Node>,
-    ) -> Result<HashValue> {
-        if pathnodes
-            .get(0)
-            .expect("pathnode should have root")
-            .is_placeholder()
-        {
-            bail!("Key is already empty")
-        }
-
-        let n = old_leaf_node.ok_or(anyhow!("old_leaf_data is None"))?;
-        let (actual_path, _) = match n {
-            Node::Leaf(content) => content,
-            _ => bail!("expected leaf"),
-        };
-        if actual_path != path {
-            bail!("Key is already empty");
+        path: HashValue<N>,
+        sidenodes: Vec<HashValue<N>>,
+        pathnodes: Vec<HashValue<N>>,
+        old_leaf_node: Option<Node<N>>,
+    ) -> Result<HashValue<N>, N> {
+        if pathnodes[0].is_placeholder() {
+            return Err(SmtError::KeyNotFound);
         }
 
-        for key in &pathnodes {
-            self.store.delete_node(key);
+        let (actual_path, _) = match old_leaf_node {
+            Some(Node::Leaf(content)) => content,
+            _ => return Err(SmtError::UnexpectedNodeKind),
+        };
+        if actual_path != path {
+            return Err(SmtError::KeyNotFound);
         }
 
-        /*
-        // TODO: finish
+        // `current` is None until we know what replaces the deleted leaf: either
+        // its sibling leaf, which bubbles up past placeholder levels, or a
+        // placeholder next to a sibling subtree that stays where it is.
+        let mut current: Option<HashValue<N>> = None;
         let mut non_placeholder_reached = false;
-        let mut current_hash: &[u8] = b"";
-        let mut current_data: &[u8] = b"";
-        for (index, snk) in sidenodes.iter().enumerate() {
-            if current_data.len() == 0 {
-                let sidenode = self
-                    .store
-                    .get_node(*snk)
-                    .and_then(|raw| Node::decode(raw))?;
-                if sidenode.is_leaf() {
-                    current_hash = snk.as_ref();
-                    current_data = snk.as_ref();
-                    continue;
-                } else {
-                    current_data = HashValue::placeholder().as_ref();
+        for (i, sidenode) in sidenodes.iter().enumerate() {
+            let current_hash = match current {
+                Some(h) => h,
+                None => {
+                    let sibling = self.get_node(*sidenode)?;
+                    if sibling.is_leaf() {
+                        current = Some(*sidenode);
+                        continue;
+                    }
                     non_placeholder_reached = true;
+                    HashValue::placeholder()
+                }
+            };
+
+            if !non_placeholder_reached {
+                if sidenode.is_placeholder() {
+                    continue;
+                }
+                non_placeholder_reached = true;
+            }
+
+            let node = match path.has_bit_set(sidenodes.len() - 1 - i) {
+                // go right
+                true => Node::new_internal(*sidenode, current_hash),
+                _ => Node::new_internal(current_hash, *sidenode),
+            };
+            current = Some(self.set_node(node)?);
+        }
+
+        Ok(current.unwrap_or_else(HashValue::placeholder))
+    }
+
+    /// Apply many updates at once, building each affected subtree only once.
+    /// Passing `DEFAULT_VALUE` deletes a key. If a key appears more than once
+    /// the last value wins, so the new root is the same as calling `update`
+    /// for each entry in order.
+    pub fn update_batch<K, V, I>(&mut self, entries: I) -> Result<(), N>
+    where
+        S: MaybeSync,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let new_root = self.update_batch_for_root(entries, self.root)?;
+        self.set_root(new_root);
+        Ok(())
+    }
+
+    /// Delete many keys at once
+    pub fn delete_batch<K, I>(&mut self, keys: I) -> Result<(), N>
+    where
+        S: MaybeSync,
+        K: AsRef<[u8]>,
+        I: IntoIterator<Item = K>,
+    {
+        self.update_batch(keys.into_iter().map(|k| (k, DEFAULT_VALUE)))
+    }
+
+    pub fn update_batch_for_root<K, V, I>(
+        &mut self,
+        entries: I,
+        root: HashValue<N>,
+    ) -> Result<HashValue<N>, N>
+    where
+        S: MaybeSync,
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let mut entries: Vec<(HashValue<N>, V)> = entries
+            .into_iter()
+            .map(|(k, v)| (H::digest(k.as_ref()), v))
+            .collect();
+        // stable, so the last entry for a path is the last one of its run
+        entries.sort_by_key(|(path, _)| *path);
+        let mut latest: Vec<(HashValue<N>, V)> = Vec::with_capacity(entries.len());
+        for (path, value) in entries {
+            match latest.last_mut() {
+                Some(last) if last.0 == path => last.1 = value,
+                _ => latest.push((path, value)),
+            }
+        }
+
+        let updates: Vec<(HashValue<N>, Option<HashValue<N>>)> = latest
+            .iter()
+            .map(|(path, value)| match value.as_ref() {
+                v if v == DEFAULT_VALUE => (*path, None),
+                v => (*path, Some(H::digest(v))),
+            })
+            .collect();
+
+        let mut writes = BatchWrites::default();
+        let new_root = self.batch_subtree(root, 0, &updates, &mut writes)?;
+
+        for (hash, node) in writes.nodes {
+            self.store.set_node(hash, node)?;
+        }
+        for ((path, value), (_, value_hash)) in latest.iter().zip(&updates) {
+            if let Some(value_hash) = value_hash {
+                self.store
+                    .set_value(value_key::<H, N>(*path, *value_hash), value.as_ref())?;
+            }
+        }
+
+        Ok(new_root)
+    }
+
+    /// Apply the sorted `updates` to the subtree `hash` at `depth`, returning
+    /// the new subtree hash. A `None` value hash deletes the path.
+    fn batch_subtree(
+        &self,
+        hash: HashValue<N>,
+        depth: usize,
+        updates: &[(HashValue<N>, Option<HashValue<N>>)],
+        writes: &mut BatchWrites<N>,
+    ) -> Result<HashValue<N>, N>
+    where
+        S: MaybeSync,
+    {
+        if updates.is_empty() {
+            return Ok(hash);
+        }
+
+        let mut leaves: Vec<(HashValue<N>, HashValue<N>)> = updates
+            .iter()
+            .filter_map(|(path, value_hash)| value_hash.map(|v| (*path, v)))
+            .collect();
+
+        if hash.is_placeholder() {
+            return self.batch_leaves(depth, &leaves, writes);
+        }
+
+        match self.get_node(hash)? {
+            Node::Leaf((path, value_hash)) => {
+                // The existing leaf stays unless one of the updates replaces it
+                let replaced = updates.binary_search_by_key(&path, |(p, _)| *p).is_ok();
+                if !replaced {
+                    let at = leaves.partition_point(|(p, _)| *p < path);
+                    leaves.insert(at, (path, value_hash));
+                }
+                let new_hash = self.batch_leaves(depth, &leaves, writes)?;
+                // already in the store
+                writes.nodes.remove(&hash);
+                Ok(new_hash)
+            }
+            Node::Internal((left, right)) => {
+                ensure!(depth < HashValue::<N>::DEPTH, SmtError::UnexpectedNodeKind);
+                let split = updates.partition_point(|(p, _)| !p.has_bit_set(depth));
+                let (l, r) = updates.split_at(split);
+
+                #[cfg(feature = "parallel")]
+                if updates.len() >= PARALLEL_BATCH {
+                    let mut right_writes = BatchWrites::default();
+                    let (new_left, new_right) = rayon::join(
+                        || self.batch_subtree(left, depth + 1, l, writes),
+                        || self.batch_subtree(right, depth + 1, r, &mut right_writes),
+                    );
+                    writes.nodes.extend(right_writes.nodes);
+                    return self.batch_join(new_left?, new_right?, writes);
+                }
+
+                let new_left = self.batch_subtree(left, depth + 1, l, writes)?;
+                let new_right = self.batch_subtree(right, depth + 1, r, writes)?;
+                self.batch_join(new_left, new_right, writes)
+            }
+        }
+    }
+
+    /// Build a subtree at `depth` holding only the sorted `leaves`
+    fn batch_leaves(
+        &self,
+        depth: usize,
+        leaves: &[(HashValue<N>, HashValue<N>)],
+        writes: &mut BatchWrites<N>,
+    ) -> Result<HashValue<N>, N>
+    where
+        S: MaybeSync,
+    {
+        match leaves {
+            [] => Ok(HashValue::placeholder()),
+            [(path, value_hash)] => {
+                let node = Node::new_leaf(*path, *value_hash);
+                let hash = node.hash::<H>();
+                writes.nodes.insert(hash, node);
+                Ok(hash)
+            }
+            _ => {
+                // Paths are deduplicated, so two leaves always part before the bottom
+                ensure!(depth < HashValue::<N>::DEPTH, SmtError::UnexpectedNodeKind);
+                let split = leaves.partition_point(|(p, _)| !p.has_bit_set(depth));
+                let (l, r) = leaves.split_at(split);
+
+                #[cfg(feature = "parallel")]
+                if leaves.len() >= PARALLEL_BATCH {
+                    let mut right_writes = BatchWrites::default();
+                    let (left, right) = rayon::join(
+                        || self.batch_leaves(depth + 1, l, writes),
+                        || self.batch_leaves(depth + 1, r, &mut right_writes),
+                    );
+                    writes.nodes.extend(right_writes.nodes);
+                    return self.batch_join(left?, right?, writes);
                 }
+
+                let left = self.batch_leaves(depth + 1, l, writes)?;
+                let right = self.batch_leaves(depth + 1, r, writes)?;
+                self.batch_join(left, right, writes)
             }
+        }
+    }
+
+    /// Join two new subtrees. A lone leaf next to an empty subtree moves up
+    /// instead of getting a parent node.
+    fn batch_join(
+        &self,
+        left: HashValue<N>,
+        right: HashValue<N>,
+        writes: &mut BatchWrites<N>,
+    ) -> Result<HashValue<N>, N> {
+        let is_leaf = |hash: HashValue<N>| -> Result<bool, N> {
+            match writes.nodes.get(&hash) {
+                Some(node) => Ok(node.is_leaf()),
+                None => Ok(self.get_node(hash)?.is_leaf()),
+            }
+        };
+
+        match (left.is_placeholder(), right.is_placeholder()) {
+            (true, true) => return Ok(HashValue::placeholder()),
+            (true, false) if is_leaf(right)? => return Ok(right),
+            (false, true) if is_leaf(left)? => return Ok(left),
+            _ => {}
+        }
+
+        let node = Node::new_internal(left, right);
+        let hash = node.hash::<H>();
+        writes.nodes.insert(hash, node);
+        Ok(hash)
+    }
+
+    pub(crate) fn get_node(&self, key: HashValue<N>) -> Result<Node<N>, N> {
+        self.store.get_node(key)?.ok_or(SmtError::MissingNode(key))
+    }
 
-            if !non_placeholder_reached && sidenode.is_placeholder() {}
-        } */
+    /// The value stored for the leaf `(path, value_hash)`
+    pub(crate) fn get_value(
+        &self,
+        path: HashValue<N>,
+        value_hash: HashValue<N>,
+    ) -> Result<Vec<u8>, N> {
+        self.store
+            .get_value(value_key::<H, N>(path, value_hash))?
+            .filter(|value| H::digest(value) == value_hash)
+            .ok_or(SmtError::MissingValue(path))
+    }
 
-        Ok(HashValue::placeholder())
+    fn set_node(&mut self, node: Node<N>) -> Result<HashValue<N>, N> {
+        let hash = node.hash::<H>();
+        self.store.set_node(hash, node)?;
+        Ok(hash)
     }
 
     fn get_sidenodes(
         &self,
-        path: HashValue,
-        root: HashValue,
+        path: HashValue<N>,
+        root: HashValue<N>,
         siblingdata: bool,
-    ) -> Result<(
-        Vec<HashValue>,
-        Vec<HashValue>,
-        Option<Node>,
-        Option<Vec<u8>>,
-    )> {
-        let snodes: Vec<HashValue> = Vec::new();
-        let pnodes: Vec<HashValue> = vec![root];
+    ) -> Result<SideNodes<N>, N> {
+        let snodes: Vec<HashValue<N>> = Vec::new();
+        let pnodes: Vec<HashValue<N>> = vec![root];
 
         if root.is_placeholder() {
             return Ok((snodes, pnodes, None, None));
         }
 
-        let node = self
-            .store
-            .get_node(root)
-            .and_then(|raw| Node::decode(raw))?;
+        let node = self.get_node(root)?;
         if node.is_leaf() {
             return Ok((snodes, pnodes, Some(node), None));
         }
@@ -229,71 +724,59 @@ impl SparseMerkleTree {
 
     fn walk_for_subnodes(
         &self,
-        path: HashValue,
-        mut sidenodes: Vec<HashValue>,
-        mut pathnodes: Vec<HashValue>,
-        current_node: Node,
+        path: HashValue<N>,
+        mut sidenodes: Vec<HashValue<N>>,
+        mut pathnodes: Vec<HashValue<N>>,
+        current_node: Node<N>,
         with_sibdata: bool,
-    ) -> Result<(
-        Vec<HashValue>,
-        Vec<HashValue>,
-        Option<Node>,
-        Option<Vec<u8>>,
-    )> {
-        let mut node = current_node;
-
-        for i in 0..HashValue::DEPTH {
+    ) -> Result<SideNodes<N>, N> {
+        let mut node = Some(current_node);
+
+        for i in 0..HashValue::<N>::DEPTH {
             let (sidenode, nodehash) = match node {
-                Node::Internal((left, right)) => match path.has_bit_set(i) {
+                Some(Node::Internal((left, right))) => match path.has_bit_set(i) {
                     // go right
                     true => (left, right),
                     _ => (right, left),
                 },
-                _ => bail!("expected internal node"),
+                _ => return Err(SmtError::UnexpectedNodeKind),
             };
 
             sidenodes.push(sidenode);
             pathnodes.push(nodehash);
 
             if nodehash.is_placeholder() {
-                sidenodes.reverse();
-                pathnodes.reverse();
-                return Ok((sidenodes, pathnodes, None, None));
+                node = None;
+                break;
             }
 
-            node = self
-                .store
-                .get_node(nodehash)
-                .and_then(|raw| Node::decode(raw))?;
-            if node.is_leaf() {
-                sidenodes.reverse();
-                pathnodes.reverse();
-                return Ok((sidenodes, pathnodes, Some(node), None));
+            let next = self.get_node(nodehash)?;
+            let is_leaf = next.is_leaf();
+            node = Some(next);
+            if is_leaf {
+                break;
             }
         }
 
-        /*
+        // The sibling of the last step, needed to make the proof updatable
+        let mut sibdata = None;
         if with_sibdata {
-            let sibdata = self.store.get_node(sidenode)?;
-            sidenodes.reverse();
-            pathnodes.reverse();
-            return Ok((
-                sidenodes,
-                pathnodes,
-                Some(current_data.clone()),
-                Some(sibdata.clone()),
-            ));
-        }*/
+            if let Some(sidenode) = sidenodes.last().filter(|s| !s.is_placeholder()) {
+                let sibling = self.get_node(*sidenode)?;
+                sibdata = Some(sibling);
+            }
+        }
 
         sidenodes.reverse();
         pathnodes.reverse();
-        Ok((sidenodes, pathnodes, Some(node), None))
+        Ok((sidenodes, pathnodes, node, sibdata))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::store::TestStore;
     use rand::rngs::OsRng;
     use rand::{Rng, RngCore};
 
@@ -324,7 +807,7 @@ mod tests {
     fn test_tree() {
         let mut tree = SparseMerkleTree::new(None);
 
-        assert!(tree.get(b"a").is_none());
+        assert!(matches!(tree.get(b"a"), Err(SmtError::KeyNotFound)));
         assert!(tree.get_root().is_placeholder());
         assert!(tree.update(b"a", b"a1").is_ok());
 
@@ -365,7 +848,374 @@ mod tests {
         assert!(!tree.get_root().is_placeholder());
 
         for (k, v) in &d {
-            assert_eq!(tree.get(k).unwrap(), v);
+            assert_eq!(&tree.get(k).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut expected = SparseMerkleTree::new(None);
+        assert!(expected.update(b"a", b"a1").is_ok());
+        assert!(expected.update(b"b", b"b1").is_ok());
+        assert!(expected.update(b"c", b"c1").is_ok());
+
+        let mut tree = SparseMerkleTree::new(None);
+        assert!(tree.update(b"a", b"a1").is_ok());
+        assert!(tree.update(b"b", b"b1").is_ok());
+        assert!(tree.update(b"d", b"d1").is_ok());
+        assert!(tree.update(b"c", b"c1").is_ok());
+        assert!(tree.update(b"d", DEFAULT_VALUE).is_ok());
+
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert!(matches!(tree.get(b"d"), Err(SmtError::KeyNotFound)));
+        assert_eq!(tree.get(b"c").unwrap(), b"c1");
+
+        // deleting a missing key leaves the root alone
+        assert!(tree.update(b"x", DEFAULT_VALUE).is_ok());
+        assert_eq!(tree.get_root(), expected.get_root());
+
+        assert!(tree.update(b"a", DEFAULT_VALUE).is_ok());
+        assert!(tree.update(b"c", DEFAULT_VALUE).is_ok());
+        assert!(tree.update(b"b", DEFAULT_VALUE).is_ok());
+        assert!(tree.get_root().is_placeholder());
+    }
+
+    #[test]
+    fn test_batch_delete() {
+        let alphabet = b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]_";
+        let mut d: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        for _ in 0..100 {
+            d.push((random_key(alphabet, 10, 20), random_value()))
+        }
+
+        let (keep, remove) = d.split_at(50);
+        let mut expected = SparseMerkleTree::new(None);
+        for (k, v) in keep {
+            assert!(expected.update(k, v).is_ok());
+        }
+
+        // insert in reverse order to check the root doesn't depend on it
+        let mut tree = SparseMerkleTree::new(None);
+        for (k, v) in d.iter().rev() {
+            assert!(tree.update(k, v).is_ok());
+        }
+        for (k, _) in remove {
+            assert!(tree.update(k, DEFAULT_VALUE).is_ok());
+        }
+        assert_eq!(tree.get_root(), expected.get_root());
+
+        for (k, _) in keep {
+            assert!(tree.update(k, DEFAULT_VALUE).is_ok());
         }
+        assert!(tree.get_root().is_placeholder());
+    }
+
+    #[test]
+    fn test_get_for_old_roots() {
+        let mut tree = SparseMerkleTree::new(None);
+        let empty = tree.get_root();
+        assert!(tree.update(b"a", b"a1").is_ok());
+        let root_a = tree.get_root();
+        assert!(tree.update(b"b", b"b1").is_ok());
+        assert!(tree.update(b"c", b"c1").is_ok());
+        let root_abc = tree.get_root();
+
+        assert!(tree.get_for_root(b"a", empty).unwrap().is_none());
+        assert_eq!(tree.get_for_root(b"a", root_a).unwrap().unwrap(), b"a1");
+        assert!(tree.get_for_root(b"b", root_a).unwrap().is_none());
+        assert_eq!(tree.get_for_root(b"b", root_abc).unwrap().unwrap(), b"b1");
+        assert!(tree.get_for_root(b"x", root_abc).unwrap().is_none());
+
+        tree.set_root(root_a);
+        assert_eq!(tree.get(b"a").unwrap(), b"a1");
+        assert!(matches!(tree.get(b"c"), Err(SmtError::KeyNotFound)));
+
+        tree.set_root(root_abc);
+        assert!(tree.update(b"c", DEFAULT_VALUE).is_ok());
+        assert!(matches!(tree.get(b"c"), Err(SmtError::KeyNotFound)));
+        assert_eq!(tree.get(b"b").unwrap(), b"b1");
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let mut tree = SparseMerkleTree::new(None);
+        assert!(matches!(tree.get(b"a"), Err(SmtError::KeyNotFound)));
+        // deleting an absent key isn't an error
+        assert!(tree.update(b"a", DEFAULT_VALUE).is_ok());
+
+        // two keys on either side of the root
+        let key = |bit: bool| {
+            (0u8..)
+                .map(|i| vec![i])
+                .find(|k| HashValue::digest_of(k).has_bit_set(0) == bit)
+                .unwrap()
+        };
+        let (lost, kept) = (key(false), key(true));
+        assert!(tree.update(&lost, b"lost").is_ok());
+        assert!(tree.update(&kept, b"kept").is_ok());
+        let root = tree.get_root();
+        let left = match tree.get_node(root).unwrap() {
+            Node::Internal((left, _)) => left,
+            _ => panic!("expected an internal root"),
+        };
+
+        let mut store = tree.into_store();
+        assert!(store.delete_node(&left).is_ok());
+        let tree: SparseMerkleTree<_> = SparseMerkleTree::with_store(store, Some(root));
+        match tree.get(&lost) {
+            Err(SmtError::MissingNode(hash)) => assert_eq!(hash, left),
+            other => panic!("expected a missing node, got {:?}", other),
+        }
+        assert!(matches!(tree.prove(&lost), Err(SmtError::MissingNode(_))));
+        assert_eq!(tree.get(&kept).unwrap(), b"kept");
+
+        assert!(matches!(
+            Node::<32>::decode(&[7u8; 65]),
+            Err(SmtError::CorruptNode)
+        ));
+    }
+
+    fn reachable<S: TreeStore>(tree: &SparseMerkleTree<S>, root: HashValue) -> Vec<HashValue> {
+        let mut found = vec![];
+        let mut stack = vec![root];
+        while let Some(hash) = stack.pop() {
+            if hash.is_placeholder() {
+                continue;
+            }
+            found.push(hash);
+            if let Node::Internal((l, r)) = tree.get_node(hash).unwrap() {
+                stack.push(l);
+                stack.push(r);
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn test_update_batch() {
+        let alphabet = b"@QWERTYUIOPASDFGHJKLZXCVBNM[/]_";
+        let mut d: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        for _ in 0..200 {
+            d.push((random_key(alphabet, 10, 20), random_value()))
+        }
+
+        let mut expected = SparseMerkleTree::new(None);
+        for (k, v) in &d[..100] {
+            assert!(expected.update(k, v).is_ok());
+        }
+        let mut tree = SparseMerkleTree::new(None);
+        assert!(tree.update_batch(d[..100].iter().rev().cloned()).is_ok());
+        assert_eq!(tree.get_root(), expected.get_root());
+
+        // overwrite, delete, insert and repeat keys in one batch
+        let mut changes: Vec<(Vec<u8>, Vec<u8>)> = vec![];
+        for (i, (k, _)) in d[..100].iter().enumerate() {
+            match i % 4 {
+                0 => changes.push((k.clone(), DEFAULT_VALUE.to_vec())),
+                1 => changes.push((k.clone(), random_value())),
+                _ => {}
+            }
+        }
+        changes.extend(d[100..].iter().cloned());
+        changes.push((d[1].0.clone(), b"last".to_vec()));
+        changes.push((b"missing".to_vec(), DEFAULT_VALUE.to_vec()));
+
+        for (k, v) in &changes {
+            assert!(expected.update(k, v).is_ok());
+        }
+        assert!(tree.update_batch(changes.iter().cloned()).is_ok());
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert_eq!(tree.get(&d[1].0).unwrap(), b"last");
+        assert!(matches!(tree.get(&d[0].0), Err(SmtError::KeyNotFound)));
+        assert_eq!(&tree.get(&d[150].0).unwrap(), &d[150].1);
+
+        let keys: Vec<Vec<u8>> = d.iter().map(|(k, _)| k.clone()).collect();
+        assert!(tree.delete_batch(keys.iter()).is_ok());
+        assert!(tree.get_root().is_placeholder());
+    }
+
+    #[test]
+    fn test_batch_writes_final_nodes_once() {
+        let mut tree: SparseMerkleTree<TestStore> =
+            SparseMerkleTree::with_store(TestStore::default(), None);
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (0..100u8).map(|i| (vec![i], vec![i, 1])).collect();
+        assert!(tree.update_batch(entries.iter().cloned()).is_ok());
+        let old_root = tree.get_root();
+        let old_nodes = reachable(&tree, old_root);
+        assert_eq!(tree.store().node_writes, old_nodes.len());
+
+        tree.store.node_writes = 0;
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = (50..150u8).map(|i| (vec![i], vec![i, 2])).collect();
+        assert!(tree.update_batch(entries.iter().cloned()).is_ok());
+        let new_nodes: Vec<HashValue> = reachable(&tree, tree.get_root())
+            .into_iter()
+            .filter(|h| !old_nodes.contains(h))
+            .collect();
+        assert_eq!(tree.store().node_writes, new_nodes.len());
+    }
+
+    #[test]
+    fn test_raw_paths() {
+        use crate::proof::{verify_proof, verify_proof_path};
+
+        let mut tree = SparseMerkleTree::new(None);
+        let mut by_path = SparseMerkleTree::new(None);
+        for i in 0..20u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+            assert!(by_path
+                .update_path(HashValue::digest_of(&[i]), &[i])
+                .is_ok());
+        }
+        assert_eq!(tree.get_root(), by_path.get_root());
+        assert_eq!(tree.get_path(HashValue::digest_of(&[3])).unwrap(), vec![3]);
+
+        // paths are used as is
+        let id = HashValue::new([7u8; 32]);
+        assert!(tree.update_path(id, b"id").is_ok());
+        assert_eq!(tree.get_path(id).unwrap(), b"id");
+        assert!(matches!(tree.get(id.as_ref()), Err(SmtError::KeyNotFound)));
+
+        let root = tree.get_root();
+        let proof = tree.prove_path(id).unwrap();
+        assert!(verify_proof_path(&proof, root, id, b"id"));
+        assert!(!verify_proof_path(&proof, root, id, b"other"));
+        assert!(!verify_proof(&proof, root, id.as_ref(), b"id"));
+
+        assert!(tree.delete_path(id).is_ok());
+        assert_eq!(tree.get_root(), by_path.get_root());
+        let proof = tree.prove_path(id).unwrap();
+        assert!(verify_proof_path(
+            &proof,
+            tree.get_root(),
+            id,
+            DEFAULT_VALUE
+        ));
+        assert!(matches!(tree.get_path(id), Err(SmtError::KeyNotFound)));
+        assert_eq!(tree.get_path_for_root(id, root).unwrap().unwrap(), b"id");
+    }
+
+    #[test]
+    fn test_old_values_survive_overwrites() {
+        let mut tree = SparseMerkleTree::new(None);
+        let mut roots = vec![];
+        for value in [&b"v1"[..], b"v2", b"v1"] {
+            assert!(tree.update(b"a", value).is_ok());
+            roots.push(tree.get_root());
+        }
+        assert!(tree.update_batch(vec![(b"a", b"v3")]).is_ok());
+        roots.push(tree.get_root());
+        assert!(tree.update(b"a", DEFAULT_VALUE).is_ok());
+
+        for (root, value) in roots.iter().zip([&b"v1"[..], b"v2", b"v1", b"v3"]) {
+            assert_eq!(tree.get_for_root(b"a", *root).unwrap().unwrap(), value);
+        }
+        // one entry per distinct value
+        assert_eq!(tree.store().value_keys().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_branch_from_old_roots() {
+        let mut tree = SparseMerkleTree::new(None);
+        assert!(tree.update(b"a", b"a1").is_ok());
+        assert!(tree.update(b"b", b"b1").is_ok());
+        assert!(tree.update(b"c", b"c1").is_ok());
+        let root_a = tree.get_root();
+
+        assert!(tree.update(b"d", b"d1").is_ok());
+        assert!(tree.update(b"a", DEFAULT_VALUE).is_ok());
+        let root_b = tree.get_root();
+
+        // branch off the older root
+        let root_c = tree.update_for_root(b"e", b"e1", root_a).unwrap();
+        let root_c = tree.update_for_root(b"b", DEFAULT_VALUE, root_c).unwrap();
+        assert_eq!(tree.get_root(), root_b);
+
+        let read = |k: &[u8], root| tree.get_for_root(k, root).unwrap();
+        assert_eq!(read(b"a", root_a).unwrap(), b"a1");
+        assert_eq!(read(b"b", root_a).unwrap(), b"b1");
+        assert!(read(b"d", root_a).is_none());
+        assert!(read(b"e", root_a).is_none());
+
+        assert!(read(b"a", root_b).is_none());
+        assert_eq!(read(b"b", root_b).unwrap(), b"b1");
+        assert_eq!(read(b"d", root_b).unwrap(), b"d1");
+        assert!(read(b"e", root_b).is_none());
+
+        assert_eq!(read(b"a", root_c).unwrap(), b"a1");
+        assert!(read(b"b", root_c).is_none());
+        assert!(read(b"d", root_c).is_none());
+        assert_eq!(read(b"e", root_c).unwrap(), b"e1");
+
+        // and the branches keep going from either side
+        let root_d = tree.update_for_root(b"f", b"f1", root_c).unwrap();
+        assert!(tree.update(b"g", b"g1").is_ok());
+        assert_eq!(tree.get_for_root(b"e", root_d).unwrap().unwrap(), b"e1");
+        assert_eq!(
+            tree.get_for_root(b"d", tree.get_root()).unwrap().unwrap(),
+            b"d1"
+        );
+        assert_eq!(tree.get_for_root(b"c", root_a).unwrap().unwrap(), b"c1");
+    }
+
+    fn check_width<const N: usize>() {
+        use crate::hasher::Blake2bHasher;
+        use crate::proof::{verify_many, verify_proof};
+
+        let mut tree: SparseMerkleTree<MemoryStore<N>, Blake2bHasher, N> = Default::default();
+        for i in 0..50u8 {
+            assert!(tree.update(&[i], &[i]).is_ok());
+        }
+        assert!(tree.update(&[7], DEFAULT_VALUE).is_ok());
+        let root = tree.get_root();
+        assert_eq!(root.iter_bits().count(), N * 8);
+        assert_eq!(tree.iter(root).count(), 49);
+        assert_eq!(tree.get(&[3]).unwrap(), vec![3]);
+
+        let proof = tree.prove(&[3]).unwrap();
+        assert!(verify_proof(&proof, root, &[3], &[3]));
+        assert!(!verify_proof(&proof, root, &[3], &[4]));
+        assert!(proof.sidenodes.len() <= N * 8);
+        let decompacted = proof.compact().unwrap().decompact().unwrap();
+        assert!(verify_proof(&decompacted, root, &[3], &[3]));
+        let proof = tree.prove(&[7]).unwrap();
+        assert!(verify_proof(&proof, root, &[7], DEFAULT_VALUE));
+
+        let keys = [vec![1u8], vec![7], vec![200]];
+        let proof = tree.prove_many(&keys).unwrap();
+        let entries = [
+            (&keys[0], vec![1u8]),
+            (&keys[1], vec![]),
+            (&keys[2], vec![]),
+        ];
+        assert!(verify_many(&proof, root, &entries));
+
+        let node = tree.store().get_node(root).unwrap().unwrap();
+        assert_eq!(node.to_bytes().len(), Node::<N>::ENCODED_LENGTH);
+        assert_eq!(Node::<N>::decode(&node.to_bytes()).unwrap(), node);
+
+        // errors carry hashes of the tree's own width
+        let unknown = HashValue::<N>::new([7u8; N]);
+        match tree.get_for_root(&[3], unknown) {
+            Err(SmtError::MissingNode(hash)) => assert_eq!(hash, unknown),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_path_widths() {
+        check_width::<20>();
+        check_width::<32>();
+        check_width::<64>();
+        assert_eq!(Node::<20>::ENCODED_LENGTH, 41);
+        assert_eq!(Node::<64>::ENCODED_LENGTH, 129);
+
+        // a wider tree hashes differently, even for the same hash function
+        let mut narrow: SparseMerkleTree<MemoryStore<20>, crate::Blake2bHasher, 20> =
+            Default::default();
+        let mut wide: SparseMerkleTree<MemoryStore, crate::Blake2bHasher> = Default::default();
+        assert!(narrow.update(b"a", b"a").is_ok());
+        assert!(wide.update(b"a", b"a").is_ok());
+        assert_eq!(narrow.get_root().as_ref().len(), 20);
+        assert_ne!(&wide.get_root().as_ref()[..20], narrow.get_root().as_ref());
     }
 }
@@ -1,34 +1,37 @@
-use anyhow::{anyhow, ensure, Result};
-use blake2::{Blake2s, Digest};
+use crate::encoding::from_hex;
+use crate::error::{Result, SmtError};
+use crate::hasher::{Blake2sHasher, TreeHasher};
 
 pub const LEAF_TAG: u8 = 0;
 pub const INTERNAL_TAG: u8 = 1;
 
-pub type EncodedNode = [u8; 65];
+/// A stored node's encoding: its tag byte and then its two hashes, `2 * N + 1`
+/// bytes for `N` byte hashes
+pub type EncodedNode = Vec<u8>;
 
 /// Used to mark a value for deletion for a given key
 pub const DEFAULT_VALUE: &[u8] = b"";
 
+/// An `N` byte hash, also used as the path of a key. Trees default to 32
+/// bytes, giving 256 levels.
 #[derive(Clone, Copy, Eq, Hash, PartialEq, PartialOrd, Ord)]
-pub struct HashValue {
-    hash: [u8; Self::LENGTH],
+pub struct HashValue<const N: usize = 32> {
+    hash: [u8; N],
 }
 
 impl HashValue {
-    pub const LENGTH: usize = 32;
-    pub const DEPTH: usize = Self::LENGTH * 8;
-
-    pub fn new(data: [u8; Self::LENGTH]) -> Self {
-        Self { hash: data }
+    /// Create a new HashValue by hashing the `data` with the default hasher (Blake2s)
+    pub fn digest_of(data: &[u8]) -> Self {
+        Blake2sHasher::digest(data)
     }
+}
 
-    /// Create a new HashValue by hashing the `data`
-    pub fn digest_of(data: &[u8]) -> Self {
-        let mut hash = [0u8; Self::LENGTH];
-        let mut hasher = Blake2s::new();
-        hasher.update(data);
-        hash.copy_from_slice(hasher.finalize().as_ref());
-        Self { hash }
+impl<const N: usize> HashValue<N> {
+    pub const LENGTH: usize = N;
+    pub const DEPTH: usize = N * 8;
+
+    pub fn new(data: [u8; N]) -> Self {
+        Self { hash: data }
     }
 
     pub fn has_bit_set(&self, index: usize) -> bool {
@@ -37,25 +40,33 @@ impl HashValue {
         (self.hash[pos] >> bit) & 1 != 0
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
+    /// Copy of this hash with the bit at `index` set to `bit`
+    pub(crate) fn with_bit(mut self, index: usize, bit: bool) -> Self {
+        let mask = 1 << (7 - index % 8);
+        match bit {
+            true => self.hash[index / 8] |= mask,
+            _ => self.hash[index / 8] &= !mask,
+        }
+        self
+    }
+
+    pub fn to_vec(self) -> Vec<u8> {
         self.hash.to_vec()
     }
 
     pub fn placeholder() -> Self {
-        Self {
-            hash: [0u8; Self::LENGTH],
-        }
+        Self { hash: [0u8; N] }
     }
 
     pub fn is_placeholder(&self) -> bool {
-        self.hash == [0u8; Self::LENGTH]
+        self.hash == [0u8; N]
     }
 
     pub fn iter_bits(&self) -> HashValueBitIterator<'_> {
         HashValueBitIterator::new(self)
     }
 
-    pub fn common_prefix_bits_len(&self, other: HashValue) -> usize {
+    pub fn common_prefix_bits_len(&self, other: Self) -> usize {
         self.iter_bits()
             .zip(other.iter_bits())
             .take_while(|(x, y)| x == y)
@@ -63,13 +74,13 @@ impl HashValue {
     }
 }
 
-impl AsRef<[u8; HashValue::LENGTH]> for HashValue {
-    fn as_ref(&self) -> &[u8; HashValue::LENGTH] {
+impl<const N: usize> AsRef<[u8; N]> for HashValue<N> {
+    fn as_ref(&self) -> &[u8; N] {
         &self.hash
     }
 }
 
-impl std::ops::Index<usize> for HashValue {
+impl<const N: usize> std::ops::Index<usize> for HashValue<N> {
     type Output = u8;
 
     fn index(&self, s: usize) -> &u8 {
@@ -77,7 +88,7 @@ impl std::ops::Index<usize> for HashValue {
     }
 }
 
-impl std::fmt::Binary for HashValue {
+impl<const N: usize> std::fmt::Binary for HashValue<N> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for byte in &self.hash {
             write!(f, "{:08b}", byte)?;
@@ -86,7 +97,7 @@ impl std::fmt::Binary for HashValue {
     }
 }
 
-impl std::fmt::LowerHex for HashValue {
+impl<const N: usize> std::fmt::LowerHex for HashValue<N> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for byte in &self.hash {
             write!(f, "{:02x}", byte)?;
@@ -95,6 +106,33 @@ impl std::fmt::LowerHex for HashValue {
     }
 }
 
+impl<const N: usize> std::fmt::Display for HashValue<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:x}", self)
+    }
+}
+
+/// Parses the `2 * N` hex digits written by `Display`
+impl<const N: usize> std::str::FromStr for HashValue<N> {
+    type Err = SmtError<N>;
+
+    fn from_str(s: &str) -> Result<Self, N> {
+        let bytes = from_hex::<N>(s)?;
+        if bytes.len() != N {
+            return Err(SmtError::InvalidEncoding("wrong hash length"));
+        }
+        let mut hash = [0u8; N];
+        hash.copy_from_slice(&bytes);
+        Ok(Self::new(hash))
+    }
+}
+
+impl<const N: usize> std::fmt::Debug for HashValue<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HashValue({:x})", self)
+    }
+}
+
 /// An iterator over `HashValue` that generates one bit for each iteration.
 pub struct HashValueBitIterator<'a> {
     hash_bytes: &'a [u8],
@@ -103,10 +141,10 @@ pub struct HashValueBitIterator<'a> {
 
 impl<'a> HashValueBitIterator<'a> {
     /// Constructs a new `HashValueBitIterator` using given `HashValue`.
-    fn new(hash_value: &'a HashValue) -> Self {
+    fn new<const N: usize>(hash_value: &'a HashValue<N>) -> Self {
         HashValueBitIterator {
             hash_bytes: hash_value.as_ref(),
-            pos: (0..HashValue::DEPTH),
+            pos: (0..HashValue::<N>::DEPTH),
         }
     }
 
@@ -130,58 +168,69 @@ impl<'a> std::iter::Iterator for HashValueBitIterator<'a> {
     }
 }
 
-pub enum Node {
-    Internal((HashValue, HashValue)),
-    Leaf((HashValue, HashValue)),
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Node<const N: usize = 32> {
+    Internal((HashValue<N>, HashValue<N>)),
+    Leaf((HashValue<N>, HashValue<N>)),
 }
 
-impl Node {
-    pub fn encode(&self) -> Result<(HashValue, EncodedNode)> {
-        let mut raw = vec![];
-        let mut bits = [0u8; 65];
+impl<const N: usize> Node<N> {
+    /// Length of an encoded node
+    pub const ENCODED_LENGTH: usize = 2 * N + 1;
+
+    /// Returns the node's hash along with its encoding
+    pub fn encode<H: TreeHasher<N>>(&self) -> (HashValue<N>, EncodedNode) {
+        (self.hash::<H>(), self.to_bytes())
+    }
+
+    /// The node's hash, without building its encoding
+    pub fn hash<H: TreeHasher<N>>(&self) -> HashValue<N> {
+        let (tag, left, right) = self.parts();
+        H::digest_parts(&[&[tag], left.as_ref(), right.as_ref()])
+    }
+
+    pub fn to_bytes(&self) -> EncodedNode {
+        let (tag, left, right) = self.parts();
+        let mut raw = Vec::with_capacity(Self::ENCODED_LENGTH);
+        raw.push(tag);
+        raw.extend(left.as_ref());
+        raw.extend(right.as_ref());
+        raw
+    }
+
+    fn parts(&self) -> (u8, &HashValue<N>, &HashValue<N>) {
         match self {
-            Node::Leaf((k, v)) => {
-                raw.push(LEAF_TAG);
-                raw.extend(k.as_ref());
-                raw.extend(v.as_ref());
-            }
-            Node::Internal((l, r)) => {
-                raw.push(INTERNAL_TAG);
-                raw.extend(l.as_ref());
-                raw.extend(r.as_ref());
-            }
+            Node::Leaf((k, v)) => (LEAF_TAG, k, v),
+            Node::Internal((l, r)) => (INTERNAL_TAG, l, r),
         }
-        bits.clone_from_slice(&raw);
-        Ok((HashValue::digest_of(&bits), bits))
     }
 
-    pub fn decode(raw: &[u8]) -> Result<Self> {
-        ensure!(raw.len() == 65, "not an encoded node");
+    pub fn decode(raw: &[u8]) -> Result<Self, N> {
+        if raw.len() != Self::ENCODED_LENGTH {
+            return Err(SmtError::CorruptNode);
+        }
         let tag = raw[0];
-        let mut left = [0; 32];
-        let mut right = [0; 32];
-        left.copy_from_slice(&raw[1..33]);
-        right.copy_from_slice(&raw[33..]);
+        let mut left = [0; N];
+        let mut right = [0; N];
+        left.copy_from_slice(&raw[1..N + 1]);
+        right.copy_from_slice(&raw[N + 1..]);
         let contents = (HashValue::new(left), HashValue::new(right));
         match tag {
             LEAF_TAG => Ok(Self::Leaf(contents)),
             INTERNAL_TAG => Ok(Self::Internal(contents)),
-            _ => Err(anyhow!("Unrecognized node tag")),
+            _ => Err(SmtError::CorruptNode),
         }
     }
 
-    pub fn new_leaf(key: HashValue, value_hash: HashValue) -> Self {
+    pub fn new_leaf(key: HashValue<N>, value_hash: HashValue<N>) -> Self {
         Node::Leaf((key, value_hash))
     }
 
-    pub fn new_internal(left: HashValue, right: HashValue) -> Self {
+    pub fn new_internal(left: HashValue<N>, right: HashValue<N>) -> Self {
         Node::Internal((left, right))
     }
 
     pub fn is_leaf(&self) -> bool {
-        match self {
-            Node::Leaf(_) => true,
-            _ => false,
-        }
+        matches!(self, Node::Leaf(_))
     }
 }
@@ -0,0 +1,5 @@
+module celestia-gen
+
+go 1.19
+
+require github.com/celestiaorg/smt v0.3.1
@@ -0,0 +1,89 @@
+// Writes ../celestia_sha256.txt from the Go celestiaorg/smt library, at the
+// version pinned in go.mod. Run from this directory:
+//
+//	go mod tidy && go run . > ../celestia_sha256.txt
+package main
+
+import (
+	"crypto/sha256"
+	"encoding/hex"
+	"fmt"
+	"strings"
+
+	"github.com/celestiaorg/smt"
+)
+
+type op struct {
+	kind, key, value string
+}
+
+func main() {
+	tree := smt.NewSparseMerkleTree(smt.NewSimpleMap(), smt.NewSimpleMap(), sha256.New())
+
+	fmt.Println("# Generated by testdata/celestia-gen from github.com/celestiaorg/smt v0.3.1 with SHA-256.")
+	fmt.Println("# Keys and values are the ASCII strings shown.")
+	fmt.Println("# `update <key> <value>` and `delete <key>` are applied in order, each followed by the root.")
+	fmt.Println("# `proof <key> <sidenodes> <leaf data> <bitmask> <num sidenodes>` is against the last root:")
+	fmt.Println("# sidenodes deepest first, comma separated, `-` for none.")
+	fmt.Println()
+
+	ops := []op{{"update", "foo", "bar"}, {"update", "baz", "qux"}, {"update", "foo", "bar2"}}
+	for i := 0; i < 16; i++ {
+		ops = append(ops, op{"update", fmt.Sprintf("key%d", i), fmt.Sprintf("value%d", i)})
+	}
+	ops = append(ops, op{"delete", "baz", ""}, op{"delete", "key3", ""}, op{"update", "key7", "seven"}, op{"delete", "missing", ""})
+
+	fmt.Printf("root %x\n", tree.Root())
+	for _, o := range ops {
+		if o.kind == "update" {
+			must(tree.Update([]byte(o.key), []byte(o.value)))
+			fmt.Printf("update %s %s\n", o.key, o.value)
+		} else {
+			// Deleting an absent key leaves the root alone
+			present, err := tree.Has([]byte(o.key))
+			check(err)
+			if present {
+				must(tree.Delete([]byte(o.key)))
+			}
+			fmt.Printf("delete %s\n", o.key)
+		}
+		fmt.Printf("root %x\n", tree.Root())
+	}
+
+	for _, key := range []string{"foo", "key0", "key7", "key15", "baz", "key3", "missing", "absent3", "absent4", "absent11"} {
+		proof, err := tree.Prove([]byte(key))
+		check(err)
+		compact, err := smt.CompactProof(proof, sha256.New())
+		check(err)
+		fmt.Printf("proof %s %s %s %s %d\n", key, join(proof.SideNodes), orDash(proof.NonMembershipLeafData),
+			orDash(compact.BitMask), compact.NumSideNodes)
+	}
+}
+
+func join(hashes [][]byte) string {
+	parts := make([]string, len(hashes))
+	for i, h := range hashes {
+		parts[i] = hex.EncodeToString(h)
+	}
+	if len(parts) == 0 {
+		return "-"
+	}
+	return strings.Join(parts, ",")
+}
+
+func orDash(data []byte) string {
+	if len(data) == 0 {
+		return "-"
+	}
+	return hex.EncodeToString(data)
+}
+
+func must(_ []byte, err error) {
+	check(err)
+}
+
+func check(err error) {
+	if err != nil {
+		panic(err)
+	}
+}